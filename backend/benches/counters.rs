@@ -0,0 +1,118 @@
+//! Vote throughput of the striped `Counters` against the previous layout,
+//! where every vote took one writer lock and bumped atomics sharing a
+//! single cache line.
+//!
+//!     cargo bench --bench counters
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Barrier, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[allow(dead_code)]
+#[path = "../src/counters.rs"]
+mod counters;
+
+#[allow(dead_code)]
+#[path = "../src/crdt.rs"]
+mod crdt;
+
+use counters::{Counters, COLORS};
+
+const THREADS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+const VOTES_PER_THREAD: u64 = 10_000;
+
+#[derive(Default)]
+struct SingleLine {
+    sequence: AtomicUsize,
+    writer: Mutex<()>,
+    colors: [AtomicUsize; 4],
+    total: AtomicUsize,
+}
+
+impl SingleLine {
+    // Mirrors the old write path, including the read-back of every counter
+    // used for the broadcast delta.
+    fn increment(&self, index: usize) -> [usize; 5] {
+        let _guard = self.writer.lock().unwrap();
+        let sequence = self.sequence.load(Relaxed);
+        self.sequence.store(sequence + 1, Relaxed);
+        self.colors[index].fetch_add(1, Relaxed);
+        self.total.fetch_add(1, Relaxed);
+        let current = [
+            self.colors[0].load(Relaxed),
+            self.colors[1].load(Relaxed),
+            self.colors[2].load(Relaxed),
+            self.colors[3].load(Relaxed),
+            self.total.load(Relaxed),
+        ];
+        self.sequence.store(sequence + 2, Relaxed);
+        current
+    }
+}
+
+fn run(threads: usize, iters: u64, vote: impl Fn(usize) + Sync) -> Duration {
+    let barrier = Barrier::new(threads + 1);
+    thread::scope(|scope| {
+        for t in 0..threads {
+            let barrier = &barrier;
+            let vote = &vote;
+            scope.spawn(move || {
+                barrier.wait();
+                for i in 0..iters * VOTES_PER_THREAD {
+                    vote((t + i as usize) % COLORS.len());
+                }
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        let started = Instant::now();
+        barrier.wait();
+        started.elapsed()
+    })
+}
+
+fn bench_counters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("votes");
+    group.sample_size(10);
+
+    for threads in THREADS {
+        group.throughput(Throughput::Elements(threads as u64 * VOTES_PER_THREAD));
+
+        group.bench_with_input(
+            BenchmarkId::new("single_line", threads),
+            &threads,
+            |b, &threads| {
+                let counters = SingleLine::default();
+                b.iter_custom(|iters| {
+                    run(threads, iters, |i| {
+                        counters.increment(i);
+                    })
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("striped", threads),
+            &threads,
+            |b, &threads| {
+                let counters = Counters::default();
+                b.iter_custom(|iters| {
+                    run(threads, iters, |i| {
+                        counters.record(&[(i, 1)]);
+                    })
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_counters);
+criterion_main!(benches);
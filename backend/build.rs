@@ -0,0 +1,21 @@
+use prost::Message;
+
+// Compiles the gRPC service. The proto files are parsed in Rust, so the
+// build needs no `protoc`.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let proto = "proto/voting/v1/poll.proto";
+    println!("cargo:rerun-if-changed={proto}");
+
+    let parsed = protobuf_parse::Parser::new()
+        .pure()
+        .include("proto")
+        .input(proto)
+        .file_descriptor_set()?;
+    let descriptors =
+        prost_types::FileDescriptorSet::decode(&*protobuf::Message::write_to_bytes(&parsed)?)?;
+
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+    Ok(())
+}
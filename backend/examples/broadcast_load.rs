@@ -0,0 +1,163 @@
+//! Fan-out load harness for the broadcast path.
+//!
+//! Opens `clients` websocket connections against a running backend, casts
+//! `broadcasts` votes from one extra connection and waits until every client has seen
+//! every resulting update. When the server pid is given, its resident memory
+//! and CPU time are read from `/proc` before and after to report the cost per
+//! broadcast.
+//!
+//!     ulimit -n 65536
+//!     cargo run --release --example broadcast_load -- ws://127.0.0.1:3000/api/ws 10000 100 <pid>
+
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use std::{
+    env, fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+// Linux reports utime/stime in clock ticks, which is 100 on every mainstream
+// kernel configuration.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+const CONNECT_BATCH: usize = 500;
+
+#[derive(Clone, Copy)]
+struct Usage {
+    rss_kib: u64,
+    cpu_seconds: f64,
+}
+
+fn usage(pid: u32) -> Option<Usage> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let rss_kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name can contain spaces, so count fields after its ')'.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+
+    Some(Usage {
+        rss_kib,
+        cpu_seconds: (utime + stime) / CLOCK_TICKS_PER_SECOND,
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let url = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "ws://127.0.0.1:3000/api/ws".into());
+    let clients: usize = args.get(1).and_then(|v| v.parse().ok()).unwrap_or(10_000);
+    let broadcasts: usize = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(100);
+    let pid: Option<u32> = args.get(3).and_then(|v| v.parse().ok());
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let dropped = Arc::new(AtomicUsize::new(0));
+
+    println!("Connecting {clients} clients to {url}");
+    let mut readers = Vec::with_capacity(clients);
+    for batch_start in (0..clients).step_by(CONNECT_BATCH) {
+        let batch = (batch_start..clients.min(batch_start + CONNECT_BATCH))
+            .map(|_| connect_async(url.as_str()));
+        for result in join_all(batch).await {
+            let (socket, _) = result.expect("Failed to connect");
+            let (_, mut stream) = socket.split();
+
+            let received = Arc::clone(&received);
+            let dropped = Arc::clone(&dropped);
+            readers.push(tokio::spawn(async move {
+                while let Some(Ok(message)) = stream.next().await {
+                    // Votes broadcast deltas without a "type" field.
+                    if let Message::Text(text) = message {
+                        if !text.contains("\"type\"") {
+                            received.fetch_add(1, Relaxed);
+                        }
+                    }
+                }
+                dropped.fetch_add(1, Relaxed);
+            }));
+        }
+    }
+
+    // Every connect broadcasts a "users" frame to everyone; let that storm
+    // settle before measuring, then vote from a connection that is not read.
+    sleep(Duration::from_secs(2)).await;
+    let (mut voter, _) = connect_async(url.as_str())
+        .await
+        .expect("Failed to connect voter");
+    sleep(Duration::from_millis(500)).await;
+    let before = pid.and_then(usage);
+
+    let started = Instant::now();
+    for _ in 0..broadcasts {
+        voter
+            .send(Message::Text("red".into()))
+            .await
+            .expect("Failed to vote");
+    }
+    let expected = || (clients - dropped.load(Relaxed)) * broadcasts;
+    let finished = timeout(Duration::from_secs(120), async {
+        while received.load(Relaxed) < expected() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    let elapsed = started.elapsed();
+    let after = pid.and_then(usage);
+
+    let delivered = received.load(Relaxed);
+    println!(
+        "Delivered {delivered}/{} frames in {:.3}s{}",
+        expected(),
+        elapsed.as_secs_f64(),
+        if finished.is_err() {
+            " (timed out)"
+        } else {
+            ""
+        }
+    );
+    println!("Clients dropped by the server: {}", dropped.load(Relaxed));
+    println!(
+        "Fan-out: {:.0} frames/s, {:.3}ms per broadcast",
+        delivered as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1000.0 / broadcasts as f64
+    );
+
+    match (before, after) {
+        (Some(before), Some(after)) => {
+            println!(
+                "Server RSS: {} KiB -> {} KiB ({:+} KiB)",
+                before.rss_kib,
+                after.rss_kib,
+                after.rss_kib as i64 - before.rss_kib as i64
+            );
+            let cpu = after.cpu_seconds - before.cpu_seconds;
+            println!(
+                "Server CPU: {:.3}s total, {:.3}ms per broadcast, {:.2}us per delivered frame",
+                cpu,
+                cpu * 1000.0 / broadcasts as f64,
+                cpu * 1_000_000.0 / delivered.max(1) as f64
+            );
+        }
+        _ => println!("Pass the server pid to report memory and CPU"),
+    }
+
+    for reader in readers {
+        reader.abort();
+    }
+}
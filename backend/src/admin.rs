@@ -0,0 +1,69 @@
+use crate::{error::AppError, state::AppState};
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use std::sync::Arc;
+
+// Handlers taking `Admin` only run for requests carrying the configured
+// bearer token. Without a token configured every admin request is refused.
+pub struct Admin;
+
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let provided = bearer_token(parts).ok_or(AppError::Unauthorized)?;
+
+        if is_admin_token(state, provided) {
+            Ok(Admin)
+        } else {
+            Err(AppError::Unauthorized)
+        }
+    }
+}
+
+// `Option<Admin>` is `None` for requests without a token, so public
+// endpoints can show admins more. A wrong token is still refused.
+impl OptionalFromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if bearer_token(parts).is_none() {
+            return Ok(None);
+        }
+        <Self as FromRequestParts<_>>::from_request_parts(parts, state)
+            .await
+            .map(Some)
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Whether `token` is the configured admin token, for callers that cannot
+/// send an `Authorization` header such as browser websockets.
+pub fn is_admin_token(state: &AppState, token: &str) -> bool {
+    state
+        .config
+        .admin_token
+        .as_deref()
+        .is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+// Compares every byte so the time taken does not reveal how much of the
+// token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
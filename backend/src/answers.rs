@@ -0,0 +1,375 @@
+use crate::{
+    admin::Admin,
+    cluster::Replica,
+    config::{MAX_PENDING_ANSWERS, MAX_PENDING_PER_VOTER},
+    error::AppError,
+    poll::{unix_now, unix_now_millis},
+    state::AppState,
+    websocket::broadcast_answer,
+};
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+// Ordered so that replicas moderating the same answer differently settle on
+// the later variant: settled beats pending, and rejected beats approved, so
+// an answer one admin turned down is never shown for another's approval.
+// A replica that already showed it takes it back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Moderation {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Answer {
+    pub id: u64,
+    // Only recorded by attributed polls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voter: Option<String>,
+    pub text: String,
+    pub submitted_at: u64,
+    pub moderation: Moderation,
+    // Pseudonym of whoever submitted it, for the per-voter cap. Not saved,
+    // so answers restored from a save only count towards the queue.
+    #[serde(skip)]
+    author: Option<String>,
+}
+
+// An answer as replicas share it, with its author's pseudonym so every
+// replica holds the voter to the same cap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedAnswer {
+    #[serde(flatten)]
+    answer: Answer,
+    author: Option<String>,
+}
+
+impl Answer {
+    /// What viewers see once the answer is approved; the voter stays private.
+    pub fn published(&self) -> Value {
+        json!({
+            "id": self.id,
+            "text": self.text,
+            "submitted_at": self.submitted_at,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    answers: Vec<Answer>,
+    pending: usize,
+    pending_by: HashMap<String, usize>,
+    // The last id handed out here.
+    issued: u64,
+    // Ids submitted or moderated here since the queue was last shared.
+    unshared: BTreeSet<u64>,
+}
+
+impl Queue {
+    fn settle(&mut self, index: usize, moderation: Moderation) {
+        let answer = &mut self.answers[index];
+        answer.moderation = moderation;
+        self.pending -= 1;
+        if let Some(author) = &answer.author {
+            if let Some(pending) = self.pending_by.get_mut(author) {
+                *pending -= 1;
+                if *pending == 0 {
+                    self.pending_by.remove(author);
+                }
+            }
+        }
+    }
+
+    fn recount(&mut self) {
+        self.pending = 0;
+        self.pending_by.clear();
+        for answer in &self.answers {
+            if answer.moderation == Moderation::Pending {
+                self.pending += 1;
+                if let Some(author) = &answer.author {
+                    *self.pending_by.entry(author.clone()).or_default() += 1;
+                }
+            }
+        }
+    }
+}
+
+// Ids are the submission time in milliseconds followed by 6 bits of the
+// replica and a 4-bit sequence, so replicas never hand out the same one in
+// practice, ids sort in submission order and stay exact in JavaScript.
+const ID_REPLICA_BITS: u32 = 6;
+const ID_SEQUENCE_BITS: u32 = 4;
+
+// Free-text answers in submission order. Ids are never reused, so an admin
+// acting on a stale queue cannot moderate the wrong answer. Only a bounded
+// number may wait for moderation, so a flood can't grow the queue faster
+// than admins get through it. Replicas share answers and their moderation.
+pub struct Answers {
+    queue: Mutex<Queue>,
+    replica: Replica,
+}
+
+impl Answers {
+    pub fn new(replica: Replica) -> Self {
+        Self {
+            queue: Mutex::default(),
+            replica,
+        }
+    }
+
+    fn next_id(&self, queue: &mut Queue) -> u64 {
+        let tag = u64::from(self.replica.digest(&self.replica.name)[0]) >> (8 - ID_REPLICA_BITS);
+        let id = (unix_now_millis() << ID_REPLICA_BITS | tag) << ID_SEQUENCE_BITS;
+        queue.issued = id.max(queue.issued + 1);
+        queue.issued
+    }
+
+    /// Queues an answer for moderation, returning its id. The voter is only
+    /// kept with the answer if `attributed`.
+    pub fn submit(&self, voter: &str, attributed: bool, text: &str) -> Result<u64, &'static str> {
+        let author = self.replica.pseudonym(voter);
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.pending >= MAX_PENDING_ANSWERS.into() {
+            return Err("Too many answers are waiting for moderation");
+        }
+        let pending = queue.pending_by.entry(author.clone()).or_default();
+        if *pending >= MAX_PENDING_PER_VOTER.into() {
+            return Err("Wait for your answers to be moderated");
+        }
+        *pending += 1;
+        queue.pending += 1;
+
+        let id = self.next_id(&mut queue);
+        let answer = Answer {
+            id,
+            voter: attributed.then(|| voter.to_string()),
+            text: text.to_string(),
+            submitted_at: unix_now(),
+            moderation: Moderation::Pending,
+            author: Some(author),
+        };
+        let index = queue.answers.partition_point(|answer| answer.id < id);
+        queue.answers.insert(index, answer);
+        queue.unshared.insert(id);
+        Ok(id)
+    }
+
+    /// Settles a pending answer. `None` if there is no pending answer with
+    /// that id.
+    pub fn moderate(&self, id: u64, moderation: Moderation) -> Option<Answer> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let index = queue
+            .answers
+            .binary_search_by_key(&id, |answer| answer.id)
+            .ok()?;
+        if queue.answers[index].moderation != Moderation::Pending {
+            return None;
+        }
+        queue.settle(index, moderation);
+        queue.unshared.insert(id);
+        Some(queue.answers[index].clone())
+    }
+
+    /// Answers for the other replicas: all of them on a full sync,
+    /// otherwise those submitted or moderated here since the last call.
+    pub fn share(&self, full: bool) -> Vec<SharedAnswer> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let unshared = mem::take(&mut queue.unshared);
+        queue
+            .answers
+            .iter()
+            .filter(|answer| full || unshared.contains(&answer.id))
+            .map(|answer| SharedAnswer {
+                answer: answer.clone(),
+                author: answer.author.clone(),
+            })
+            .collect()
+    }
+
+    /// Takes in answers from another replica, returning those whose
+    /// approval changed here: approved there but not yet here, or rejected
+    /// there after being approved here.
+    pub fn merge(&self, shared: Vec<SharedAnswer>) -> Vec<Answer> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let mut changed = Vec::new();
+        for SharedAnswer { mut answer, author } in shared {
+            answer.author = author;
+            match queue
+                .answers
+                .binary_search_by_key(&answer.id, |known| known.id)
+            {
+                Ok(index) => {
+                    let known = &mut queue.answers[index];
+                    if answer.moderation > known.moderation {
+                        let was_approved = known.moderation == Moderation::Approved;
+                        known.moderation = answer.moderation;
+                        if was_approved || answer.moderation == Moderation::Approved {
+                            changed.push(known.clone());
+                        }
+                    }
+                }
+                Err(index) => {
+                    if answer.moderation == Moderation::Approved {
+                        changed.push(answer.clone());
+                    }
+                    queue.answers.insert(index, answer);
+                }
+            }
+        }
+        queue.recount();
+        changed
+    }
+
+    pub fn with_moderation(&self, moderation: Moderation) -> Vec<Answer> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .answers
+            .iter()
+            .filter(|answer| answer.moderation == moderation)
+            .cloned()
+            .collect()
+    }
+
+    pub fn published(&self) -> Vec<Value> {
+        self.with_moderation(Moderation::Approved)
+            .iter()
+            .map(Answer::published)
+            .collect()
+    }
+
+    pub fn saved(&self) -> Vec<Answer> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .answers
+            .clone()
+    }
+
+    pub fn restore(&self, saved: &[Answer]) {
+        let mut answers = saved.to_vec();
+        answers.sort_by_key(|answer| answer.id);
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        *queue = Queue {
+            issued: answers.last().map_or(0, |answer| answer.id),
+            answers,
+            ..Queue::default()
+        };
+        queue.recount();
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnswersQuery {
+    #[serde(default)]
+    moderation: Moderation,
+}
+
+pub async fn answers_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AnswersQuery>,
+) -> Json<Vec<Answer>> {
+    Json(
+        state
+            .session
+            .current()
+            .answers
+            .with_moderation(query.moderation),
+    )
+}
+
+pub async fn approve_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Answer>, AppError> {
+    let answer = moderate(&state, id, Moderation::Approved)?;
+    state.session.current().wordcloud.add(&answer.text);
+    broadcast_answer(&state, &answer);
+    Ok(Json(answer))
+}
+
+pub async fn reject_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Answer>, AppError> {
+    moderate(&state, id, Moderation::Rejected).map(Json)
+}
+
+fn moderate(state: &AppState, id: u64, moderation: Moderation) -> Result<Answer, AppError> {
+    let answer = state
+        .session
+        .current()
+        .answers
+        .moderate(id, moderation)
+        .ok_or_else(|| AppError::NotFound(format!("No pending answer with id {id}")))?;
+    info!("Answer {} is now {:?}", id, moderation);
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn moderated(answers: &Answers, id: u64, moderation: Moderation) -> Vec<SharedAnswer> {
+        answers.moderate(id, moderation).unwrap();
+        answers.share(false)
+    }
+
+    #[test]
+    fn rejection_elsewhere_revokes_an_approved_answer() {
+        let config = Config::test("a");
+        let (a, b) = (
+            Answers::new(Replica::new(&config)),
+            Answers::new(Replica::new(&config)),
+        );
+        let id = a.submit("voter", false, "hello").unwrap();
+        assert!(b.merge(a.share(false)).is_empty());
+
+        // Two admins settle it at once, one on each replica.
+        let approval = moderated(&a, id, Moderation::Approved);
+        let rejection = moderated(&b, id, Moderation::Rejected);
+
+        let revoked = a.merge(rejection);
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].moderation, Moderation::Rejected);
+        assert!(a.published().is_empty());
+        // The approval arriving late changes nothing.
+        assert!(b.merge(approval).is_empty());
+        assert!(b.published().is_empty());
+    }
+
+    #[test]
+    fn approval_elsewhere_publishes_once() {
+        let config = Config::test("a");
+        let (a, b) = (
+            Answers::new(Replica::new(&config)),
+            Answers::new(Replica::new(&config)),
+        );
+        let id = a.submit("voter", false, "hello").unwrap();
+        let approval = moderated(&a, id, Moderation::Approved);
+
+        let approved = b.merge(approval.clone());
+        assert_eq!(approved.len(), 1);
+        assert_eq!(approved[0].moderation, Moderation::Approved);
+        assert!(b.merge(approval).is_empty());
+        assert_eq!(b.published().len(), 1);
+    }
+}
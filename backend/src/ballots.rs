@@ -0,0 +1,660 @@
+use crate::{
+    admin::Admin,
+    cluster::Replica,
+    counters::COLORS,
+    crdt::Stamp,
+    error::AppError,
+    poll::{unix_now, BallotMode, Privacy},
+    state::AppState,
+    store::CounterStore,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+use tracing::{debug, info};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Round {
+    pub tallies: BTreeMap<&'static str, usize>,
+    pub exhausted: usize,
+    pub eliminated: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Runoff {
+    pub rounds: Vec<Round>,
+    pub winner: Option<&'static str>,
+}
+
+// A validated ballot: the amount it adds to each option index and, for
+// ranked polls, every preference as option indexes, most preferred first.
+#[derive(Debug, Clone, Default)]
+pub struct Cast {
+    pub tallies: Vec<(usize, usize)>,
+    pub ranking: Vec<u8>,
+}
+
+// On disk ballots name their options so reordering COLORS cannot shift them.
+// Only ballots of attributed polls say who cast them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedBallot {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cast_at: Option<u64>,
+    pub tallies: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranking: Vec<String>,
+}
+
+// What an anonymous ranked poll saves for its runoff: how many ballots
+// ranked the options a given way, without who cast them or in what order.
+#[derive(Serialize, Deserialize)]
+pub struct SavedRanking {
+    pub ranking: Vec<String>,
+    pub ballots: usize,
+}
+
+// A ledger entry as replicas share it: whose it is, when it was written,
+// and the standing ballot, or `None` once retracted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedBallot {
+    key: String,
+    stamp: Stamp,
+    ballot: Option<SavedBallot>,
+}
+
+// Who cast a ballot, kept only by attributed polls.
+struct Identity {
+    voter: Arc<str>,
+    name: Option<String>,
+    // Unix seconds.
+    cast_at: u64,
+}
+
+struct Recorded {
+    stamp: Stamp,
+    identity: Option<Identity>,
+    cast: Cast,
+}
+
+impl Recorded {
+    fn saved(&self) -> SavedBallot {
+        SavedBallot {
+            voter: self
+                .identity
+                .as_ref()
+                .map(|identity| identity.voter.to_string()),
+            name: self
+                .identity
+                .as_ref()
+                .and_then(|identity| identity.name.clone()),
+            cast_at: self.identity.as_ref().map(|identity| identity.cast_at),
+            tallies: self
+                .cast
+                .tallies
+                .iter()
+                .map(|&(index, amount)| (COLORS[index].to_string(), amount))
+                .collect(),
+            ranking: self
+                .cast
+                .ranking
+                .iter()
+                .map(|&i| COLORS[usize::from(i)].to_string())
+                .collect(),
+        }
+    }
+
+    // Identities are dropped if the poll is anonymous.
+    fn from_saved(ballot: &SavedBallot, privacy: Privacy, stamp: Stamp) -> Self {
+        let tallies = ballot
+            .tallies
+            .iter()
+            .filter_map(|(option, &amount)| {
+                let index = COLORS.iter().position(|color| color == option)?;
+                Some((index, amount))
+            })
+            .collect();
+        let ranking = parse_ranking(&ballot.ranking).unwrap_or_default();
+        let identity = match (privacy, &ballot.voter) {
+            (Privacy::Attributed, Some(voter)) => Some(Identity {
+                voter: voter.as_str().into(),
+                name: ballot.name.clone(),
+                cast_at: ballot.cast_at.unwrap_or_default(),
+            }),
+            _ => None,
+        };
+        Self {
+            stamp,
+            identity,
+            cast: Cast { tallies, ranking },
+        }
+    }
+}
+
+// The standing ballot of every voter who can still amend it and when each
+// retracted one was taken back, plus the rankings restored from a save that
+// no longer says whose they are, with how many ballots ranked the options
+// that way.
+#[derive(Default)]
+struct Ledger {
+    by_voter: HashMap<Arc<str>, Recorded>,
+    retracted: HashMap<Arc<str>, Stamp>,
+    restored: BTreeMap<Vec<u8>, usize>,
+    // Unix milliseconds of the save restored, whose counts already hold
+    // every ballot cast before it.
+    restored_at: u64,
+    // Keys written here since the ledger was last shared.
+    unshared: HashSet<Arc<str>>,
+}
+
+impl Ledger {
+    fn stamp(&self, key: &str) -> Option<&Stamp> {
+        self.by_voter
+            .get(key)
+            .map(|recorded| &recorded.stamp)
+            .or_else(|| self.retracted.get(key))
+    }
+
+    fn shared(&self, key: Arc<str>) -> Option<SharedBallot> {
+        match self.by_voter.get(&key) {
+            Some(recorded) => Some(SharedBallot {
+                key: key.to_string(),
+                stamp: recorded.stamp.clone(),
+                ballot: Some(recorded.saved()),
+            }),
+            None => self.retracted.get(&key).map(|stamp| SharedBallot {
+                key: key.to_string(),
+                stamp: stamp.clone(),
+                ballot: None,
+            }),
+        }
+    }
+
+    fn rankings(&self) -> BTreeMap<&[u8], usize> {
+        let mut rankings: BTreeMap<&[u8], usize> = self
+            .restored
+            .iter()
+            .map(|(ranking, &ballots)| (ranking.as_slice(), ballots))
+            .collect();
+        for recorded in self.by_voter.values() {
+            if !recorded.cast.ranking.is_empty() {
+                *rankings.entry(&recorded.cast.ranking).or_default() += 1;
+            }
+        }
+        rankings
+    }
+}
+
+// Single-choice taps on anonymous polls and quiz answers can't be amended,
+// so they only go to the lock-free counters. Everything else keeps one
+// ballot per voter in the ledger, which a later ballot from the same voter
+// replaces. Counters are updated while the ledger is locked, so the two
+// always agree on which ballots are live. Replicas share their ledgers and
+// keep the latest entry for each voter, each moving its own counters along,
+// so retracting or changing a ballot works on any of them.
+pub struct Ballots {
+    privacy: Privacy,
+    amendable: bool,
+    // Anonymous ledgers index ballots by the voter's pseudonym instead of
+    // the voter. Anonymous ledgers are never saved, so after every replica
+    // restarted those ballots can no longer be retracted or changed.
+    replica: Replica,
+    ledger: Mutex<Ledger>,
+    dirty: AtomicBool,
+}
+
+impl Ballots {
+    pub fn new(privacy: Privacy, ballot: BallotMode, replica: Replica) -> Self {
+        let amendable = match ballot {
+            BallotMode::Single => privacy == Privacy::Attributed,
+            BallotMode::Quiz => false,
+            _ => true,
+        };
+        Self {
+            privacy,
+            amendable,
+            replica,
+            ledger: Mutex::default(),
+            dirty: AtomicBool::default(),
+        }
+    }
+
+    /// Whether voters can retract or change their ballots.
+    pub fn amendable(&self) -> bool {
+        self.amendable
+    }
+
+    fn key(&self, voter: &str) -> Arc<str> {
+        match self.privacy {
+            Privacy::Attributed => voter.into(),
+            Privacy::Anonymous => self.replica.pseudonym(voter).into(),
+        }
+    }
+
+    /// Records a ballot for `voter`, replacing their standing one if the
+    /// poll keeps it. `name` is only kept by attributed polls.
+    pub fn cast(&self, voter: &str, name: Option<&str>, cast: Cast, counters: &dyn CounterStore) {
+        if !self.amendable {
+            counters.record(&cast.tallies);
+            return;
+        }
+
+        let identity = (self.privacy == Privacy::Attributed).then(|| Identity {
+            voter: voter.into(),
+            name: name.map(str::to_string),
+            cast_at: unix_now(),
+        });
+        let key = self.key(voter);
+
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let stamp = Stamp::after(ledger.stamp(&key), &self.replica.name);
+        let standing = ledger.by_voter.get(&key).map(|recorded| &recorded.cast);
+        counters.replace(
+            standing.map(|cast| cast.tallies.as_slice()),
+            Some(&cast.tallies),
+        );
+        ledger.retracted.remove(&key);
+        ledger.unshared.insert(Arc::clone(&key));
+        ledger.by_voter.insert(
+            key,
+            Recorded {
+                stamp,
+                identity,
+                cast,
+            },
+        );
+        self.dirty.store(true, Relaxed);
+    }
+
+    /// Takes back the voter's ballot. `false` if the voter has none.
+    pub fn retract(&self, voter: &str, counters: &dyn CounterStore) -> bool {
+        let key = self.key(voter);
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(recorded) = ledger.by_voter.remove(&key) else {
+            return false;
+        };
+        counters.retract(&recorded.cast.tallies);
+        let stamp = Stamp::after(Some(&recorded.stamp), &self.replica.name);
+        ledger.unshared.insert(Arc::clone(&key));
+        ledger.retracted.insert(key, stamp);
+        self.dirty.store(true, Relaxed);
+        true
+    }
+
+    /// Replaces the voter's ballot in one counter write. `false` if the
+    /// voter has none.
+    pub fn change(&self, voter: &str, cast: Cast, counters: &dyn CounterStore) -> bool {
+        let key = self.key(voter);
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(recorded) = ledger.by_voter.get_mut(&key) else {
+            return false;
+        };
+        counters.replace(Some(&recorded.cast.tallies), Some(&cast.tallies));
+        recorded.stamp = Stamp::after(Some(&recorded.stamp), &self.replica.name);
+        recorded.cast = cast;
+        ledger.unshared.insert(key);
+        self.dirty.store(true, Relaxed);
+        true
+    }
+
+    /// Ledger entries for the other replicas: all of them on a full sync,
+    /// otherwise those written here since the last call.
+    pub fn share(&self, full: bool) -> Vec<SharedBallot> {
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let unshared = mem::take(&mut ledger.unshared);
+        let keys: Vec<Arc<str>> = if full {
+            ledger
+                .by_voter
+                .keys()
+                .chain(ledger.retracted.keys())
+                .cloned()
+                .collect()
+        } else {
+            unshared.into_iter().collect()
+        };
+        keys.into_iter()
+            .filter_map(|key| ledger.shared(key))
+            .collect()
+    }
+
+    /// Takes the entries another replica wrote later than the ones here,
+    /// moving this replica's counters along. Ballots cast before the save
+    /// this replica restored are in its counts already.
+    pub fn merge(&self, shared: Vec<SharedBallot>, counters: &dyn CounterStore) {
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let mut merged = false;
+        for shared in shared {
+            let key: Arc<str> = shared.key.into();
+            if ledger
+                .stamp(&key)
+                .is_some_and(|stamp| *stamp >= shared.stamp)
+            {
+                continue;
+            }
+            merged = true;
+            let standing = ledger.by_voter.remove(&key);
+            ledger.retracted.remove(&key);
+            let Some(ballot) = shared.ballot else {
+                if let Some(standing) = standing {
+                    counters.replicate(Some(&standing.cast.tallies), None);
+                }
+                ledger.retracted.insert(key, shared.stamp);
+                continue;
+            };
+
+            let counted = shared.stamp.at > ledger.restored_at;
+            let recorded = Recorded::from_saved(&ballot, self.privacy, shared.stamp);
+            match standing {
+                Some(standing) => {
+                    counters.replicate(Some(&standing.cast.tallies), Some(&recorded.cast.tallies))
+                }
+                None if counted => counters.replicate(None, Some(&recorded.cast.tallies)),
+                // The save kept this ballot's ranking without whose it was.
+                None => {
+                    if let Some(ballots) = ledger.restored.get_mut(&recorded.cast.ranking) {
+                        *ballots -= 1;
+                        if *ballots == 0 {
+                            ledger.restored.remove(&recorded.cast.ranking);
+                        }
+                    }
+                }
+            }
+            ledger.by_voter.insert(key, recorded);
+        }
+        if merged {
+            self.dirty.store(true, Relaxed);
+        }
+    }
+
+    /// The ballots of an attributed poll with who cast them, in the order
+    /// they were last cast. Anonymous polls only keep counts on disk, so
+    /// this is empty for them.
+    pub fn saved(&self) -> Vec<SavedBallot> {
+        if self.privacy != Privacy::Attributed {
+            return Vec::new();
+        }
+        let ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        let mut live: Vec<&Recorded> = ledger.by_voter.values().collect();
+        live.sort_by_key(|recorded| {
+            recorded
+                .identity
+                .as_ref()
+                .map(|identity| (identity.cast_at, Arc::clone(&identity.voter)))
+        });
+        live.into_iter().map(Recorded::saved).collect()
+    }
+
+    /// What an anonymous ranked poll saves for its runoff. Empty for every
+    /// other poll.
+    pub fn rankings(&self) -> Vec<SavedRanking> {
+        if self.privacy == Privacy::Attributed {
+            return Vec::new();
+        }
+        self.ledger
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .rankings()
+            .into_iter()
+            .map(|(ranking, ballots)| SavedRanking {
+                ranking: ranking
+                    .iter()
+                    .map(|&i| COLORS[usize::from(i)].to_string())
+                    .collect(),
+                ballots,
+            })
+            .collect()
+    }
+
+    /// Rebuilds the ledger from a save made at `saved_at`, in Unix
+    /// milliseconds. The counters are restored from their own snapshot,
+    /// which also covers votes cast before the ledger existed. Ballots saved
+    /// without a voter, or by a poll that has since become anonymous, only
+    /// keep their ranking.
+    pub fn restore(&self, saved: &[SavedBallot], rankings: &[SavedRanking], saved_at: u64) {
+        let mut ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        *ledger = Ledger {
+            restored_at: saved_at,
+            ..Ledger::default()
+        };
+        for saved in rankings {
+            if let Ok(ranking) = parse_ranking(&saved.ranking) {
+                *ledger.restored.entry(ranking).or_default() += saved.ballots;
+            }
+        }
+        for ballot in saved {
+            // Older than whatever replicas wrote since.
+            let stamp = Stamp {
+                at: ballot.cast_at.unwrap_or_default() * 1000,
+                node: String::new(),
+            };
+            let recorded = Recorded::from_saved(ballot, self.privacy, stamp);
+            // Saves from before one ballot was kept per voter may list a
+            // voter more than once; the last one is their standing ballot.
+            let unkeyed = match recorded.identity.as_ref().filter(|_| self.amendable) {
+                Some(identity) => {
+                    let key = Arc::clone(&identity.voter);
+                    ledger.by_voter.insert(key, recorded)
+                }
+                None => Some(recorded),
+            };
+            if let Some(recorded) = unkeyed.filter(|recorded| !recorded.cast.ranking.is_empty()) {
+                *ledger.restored.entry(recorded.cast.ranking).or_default() += 1;
+            }
+        }
+        self.dirty.store(true, Relaxed);
+    }
+
+    /// Whether ballots changed since the last call.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Relaxed)
+    }
+
+    pub fn runoff(&self) -> Runoff {
+        let ledger = self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+        instant_runoff(&ledger.rankings())
+    }
+}
+
+/// Validates a ranking of option ids, most preferred first.
+pub fn parse_ranking(ranking: &[String]) -> Result<Vec<u8>, &'static str> {
+    if ranking.is_empty() {
+        return Err("Ranking must list at least one option");
+    }
+
+    let mut ballot = Vec::with_capacity(ranking.len());
+    for option in ranking {
+        let index = COLORS
+            .iter()
+            .position(|color| color == option)
+            .ok_or("Ranking contains an unknown option")?;
+        let index = index as u8;
+        if ballot.contains(&index) {
+            return Err("Ranking lists an option more than once");
+        }
+        ballot.push(index);
+    }
+
+    Ok(ballot)
+}
+
+// Each round counts every ballot for its highest-ranked remaining option. An
+// option with a strict majority of the continuing ballots wins; otherwise all
+// options tied for last are eliminated together. If every remaining option is
+// tied there is no winner.
+fn instant_runoff(rankings: &BTreeMap<&[u8], usize>) -> Runoff {
+    let mut active = [true; COLORS.len()];
+    let mut rounds = Vec::new();
+
+    loop {
+        let mut counts = [0usize; COLORS.len()];
+        let mut exhausted = 0;
+        for (ranking, &ballots) in rankings {
+            match ranking.iter().find(|&&i| active[usize::from(i)]) {
+                Some(&i) => counts[usize::from(i)] += ballots,
+                None => exhausted += ballots,
+            }
+        }
+
+        let mut round = Round {
+            tallies: (0..COLORS.len())
+                .filter(|&i| active[i])
+                .map(|i| (COLORS[i], counts[i]))
+                .collect(),
+            exhausted,
+            eliminated: Vec::new(),
+        };
+
+        let continuing: usize = counts.iter().sum();
+        let remaining: Vec<usize> = (0..COLORS.len()).filter(|&i| active[i]).collect();
+        let leader = remaining.iter().copied().max_by_key(|&i| counts[i]);
+        let fewest = remaining.iter().map(|&i| counts[i]).min();
+        let lowest: Vec<usize> = remaining
+            .iter()
+            .copied()
+            .filter(|&i| Some(counts[i]) == fewest)
+            .collect();
+
+        let winner = match leader {
+            Some(i) if continuing > 0 && (counts[i] * 2 > continuing || remaining.len() == 1) => {
+                Some(COLORS[i])
+            }
+            _ => None,
+        };
+        if winner.is_some() || continuing == 0 || lowest.len() == remaining.len() {
+            rounds.push(round);
+            return Runoff { rounds, winner };
+        }
+
+        for i in lowest {
+            active[i] = false;
+            round.eliminated.push(COLORS[i]);
+        }
+        rounds.push(round);
+    }
+}
+
+pub async fn runoff_handler(
+    admin: Option<Admin>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Runoff>, AppError> {
+    debug!("Runoff tally requested");
+    let poll = state.session.current();
+    if admin.is_none() && !poll.results_visible() {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(Json(poll.ballots.runoff()))
+}
+
+/// Downloads the ballots of an attributed poll along with who cast them.
+pub async fn ballots_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<Json<Vec<SavedBallot>>, AppError> {
+    let poll = state
+        .session
+        .polls()
+        .get(index)
+        .ok_or_else(|| AppError::NotFound(format!("No poll {index} in the session")))?;
+    if poll.privacy != Privacy::Attributed {
+        return Err(AppError::Forbidden(format!("Poll {index} is anonymous")));
+    }
+
+    info!("Attributed ballots of poll {} downloaded", index);
+    Ok(Json(poll.ballots.saved()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rankings use option indexes: 0 red, 1 green, 2 blue, 3 purple.
+    fn runoff(rankings: &[(&'static [u8], usize)]) -> Runoff {
+        instant_runoff(&rankings.iter().copied().collect())
+    }
+
+    fn tallies(counts: &[(&'static str, usize)]) -> BTreeMap<&'static str, usize> {
+        counts.iter().copied().collect()
+    }
+
+    #[test]
+    fn an_outright_majority_wins_the_first_round() {
+        let runoff = runoff(&[(&[0, 1], 3), (&[1], 2)]);
+
+        assert_eq!(runoff.winner, Some("red"));
+        assert_eq!(runoff.rounds.len(), 1);
+        assert!(runoff.rounds[0].eliminated.is_empty());
+    }
+
+    #[test]
+    fn eliminated_options_pass_ballots_to_the_next_preference() {
+        let runoff = runoff(&[(&[0, 2], 3), (&[1], 4), (&[2, 0], 2), (&[3, 0], 1)]);
+
+        assert_eq!(runoff.winner, Some("red"));
+        let eliminated: Vec<_> = runoff.rounds.iter().map(|r| r.eliminated.clone()).collect();
+        assert_eq!(eliminated, [vec!["purple"], vec!["blue"], vec![]]);
+        assert_eq!(
+            runoff.rounds[1].tallies,
+            tallies(&[("red", 4), ("green", 4), ("blue", 2)])
+        );
+        assert_eq!(
+            runoff.rounds[2].tallies,
+            tallies(&[("red", 6), ("green", 4)])
+        );
+    }
+
+    #[test]
+    fn options_tied_for_last_are_eliminated_together() {
+        let runoff = runoff(&[(&[0], 3), (&[1], 3), (&[2, 1], 1), (&[3, 1], 1)]);
+
+        assert_eq!(runoff.rounds[0].eliminated, ["blue", "purple"]);
+        assert_eq!(
+            runoff.rounds[1].tallies,
+            tallies(&[("red", 3), ("green", 5)])
+        );
+        assert_eq!(runoff.winner, Some("green"));
+    }
+
+    #[test]
+    fn exhausted_ballots_leave_the_majority_to_those_still_in_play() {
+        let runoff = runoff(&[(&[0], 4), (&[1], 3), (&[2], 2), (&[3], 2)]);
+
+        // Red has 4 of 11 ballots but 4 of the 7 still ranking an option.
+        assert_eq!(runoff.rounds.len(), 2);
+        assert_eq!(runoff.rounds[1].exhausted, 4);
+        assert_eq!(runoff.winner, Some("red"));
+    }
+
+    #[test]
+    fn a_tie_between_every_remaining_option_has_no_winner() {
+        let runoff = runoff(&[(&[0], 1), (&[1], 1)]);
+
+        assert_eq!(runoff.rounds.len(), 2);
+        assert_eq!(runoff.rounds[0].eliminated, ["blue", "purple"]);
+        assert!(runoff.rounds[1].eliminated.is_empty());
+        assert_eq!(runoff.winner, None);
+    }
+
+    #[test]
+    fn an_empty_ledger_has_one_empty_round() {
+        let runoff = runoff(&[]);
+
+        assert_eq!(runoff.winner, None);
+        assert_eq!(runoff.rounds.len(), 1);
+        assert_eq!(runoff.rounds[0].tallies.values().sum::<usize>(), 0);
+        assert_eq!(runoff.rounds[0].exhausted, 0);
+    }
+}
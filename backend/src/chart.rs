@@ -0,0 +1,277 @@
+use crate::{
+    counters::COLORS,
+    error::AppError,
+    poll::{Poll, PollStatus},
+    polls::shown,
+};
+use std::fmt::Write;
+
+// Layout shared by both renderers, in pixels.
+pub const WIDTH: u32 = 480;
+pub const HEIGHT: u32 = TOP + ROW * COLORS.len() as u32 + FOOTER;
+const TOP: u32 = 56;
+const ROW: u32 = 40;
+const FOOTER: u32 = 32;
+const MARGIN: u32 = 16;
+const LABEL: u32 = 100;
+const BAR: u32 = 24;
+const BAR_MAX: u32 = 248;
+const COUNT: u32 = LABEL + BAR_MAX + 12;
+
+const BACKGROUND: [u8; 3] = [0xfa, 0xf4, 0xee];
+const INK: [u8; 3] = [0x5e, 0x57, 0x57];
+// As the Svelte bar chart draws them.
+const FILLS: [[u8; 3]; 4] = [
+    [0xd9, 0x5b, 0x5b],
+    [0x6c, 0xd8, 0x59],
+    [0x5b, 0x98, 0xd9],
+    [0xd0, 0x64, 0xdd],
+];
+
+/// What the audience may see of a poll, laid out as bars.
+pub struct Chart {
+    pub title: String,
+    rows: Vec<Row>,
+    footer: String,
+}
+
+struct Row {
+    option: &'static str,
+    fill: [u8; 3],
+    count: usize,
+    // Share of all counts, 0 to 1.
+    share: f64,
+}
+
+impl Chart {
+    pub fn new(index: usize, poll: &Poll) -> Self {
+        let shown = shown(poll, false);
+        let hidden = !poll.results_visible();
+        let rows = COLORS
+            .iter()
+            .zip(FILLS)
+            .map(|(&option, fill)| {
+                let count = shown.get(option).unwrap_or_default();
+                Row {
+                    option,
+                    fill,
+                    count,
+                    share: if shown.total == 0 {
+                        0.0
+                    } else {
+                        count as f64 / shown.total as f64
+                    },
+                }
+            })
+            .collect();
+
+        let mut footer = match shown.ballots {
+            1 => "1 ballot".to_string(),
+            ballots => format!("{ballots} ballots"),
+        };
+        if hidden {
+            footer.push_str(", results hidden");
+        } else if poll.status() == PollStatus::Closed {
+            footer.push_str(", closed");
+        }
+
+        Self {
+            title: poll
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Poll {}", index + 1)),
+            rows,
+            footer,
+        }
+    }
+
+    fn count(row: &Row) -> String {
+        format!("{} ({:.0}%)", row.count, row.share * 100.0)
+    }
+
+    pub fn svg(&self) -> String {
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}" role="img" aria-label="{title}" font-family="sans-serif" fill="{ink}"><rect width="{WIDTH}" height="{HEIGHT}" fill="{background}"/><text x="{MARGIN}" y="36" font-size="20" font-weight="bold">{title}</text>"#,
+            title = escape(&self.title),
+            ink = hex(INK),
+            background = hex(BACKGROUND),
+        );
+        for (i, row) in self.rows.iter().enumerate() {
+            let top = TOP + ROW * i as u32;
+            let text = top + BAR / 2 + 5;
+            let _ = write!(
+                svg,
+                r#"<text x="{MARGIN}" y="{text}" font-size="15">{option}</text><rect x="{LABEL}" y="{top}" width="{width}" height="{BAR}" rx="8" fill="{fill}" stroke="{ink}" stroke-width="2"/><text x="{x}" y="{text}" font-size="15">{count}</text>"#,
+                option = row.option,
+                width = bar_width(row.share),
+                fill = hex(row.fill),
+                ink = hex(INK),
+                x = COUNT,
+                count = Self::count(row),
+            );
+        }
+        let _ = write!(
+            svg,
+            r#"<text x="{MARGIN}" y="{y}" font-size="13">{footer}</text></svg>"#,
+            y = HEIGHT - 12,
+            footer = escape(&self.footer),
+        );
+        svg
+    }
+
+    pub fn png(&self) -> Result<Vec<u8>, AppError> {
+        let mut canvas = Canvas::new();
+        canvas.text(MARGIN, 22, 3, &self.title);
+        for (i, row) in self.rows.iter().enumerate() {
+            let top = TOP + ROW * i as u32;
+            let text = top + (BAR - GLYPH_HEIGHT * 2) / 2;
+            canvas.text(MARGIN, text, 2, row.option);
+            let width = bar_width(row.share);
+            canvas.rect(LABEL, top, width, BAR, INK);
+            if width > 4 {
+                canvas.rect(LABEL + 2, top + 2, width - 4, BAR - 4, row.fill);
+            }
+            canvas.text(COUNT, text, 2, &Self::count(row));
+        }
+        canvas.text(MARGIN, HEIGHT - 24, 2, &self.footer);
+        canvas.encode()
+    }
+}
+
+// Every bar keeps a sliver, so empty options still show their colour.
+fn bar_width(share: f64) -> u32 {
+    (BAR_MAX as f64 * share).round().max(4.0) as u32
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+// RGB pixels, drawn with rectangles and a built-in 5x7 font so no font files
+// are needed.
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self {
+            pixels: BACKGROUND.repeat((WIDTH * HEIGHT) as usize),
+        }
+    }
+
+    fn rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: [u8; 3]) {
+        for row in y..(y + height).min(HEIGHT) {
+            for column in x..(x + width).min(WIDTH) {
+                let at = ((row * WIDTH + column) * 3) as usize;
+                self.pixels[at..at + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    // Upper case only; text running past the right edge is cut off.
+    fn text(&mut self, x: u32, y: u32, scale: u32, text: &str) {
+        let advance = (GLYPH_WIDTH + 1) * scale;
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i as u32 * advance;
+            if left + advance > WIDTH - MARGIN {
+                break;
+            }
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0 {
+                        self.rect(
+                            left + column * scale,
+                            y + row as u32 * scale,
+                            scale,
+                            scale,
+                            INK,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode(self) -> Result<Vec<u8>, AppError> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, WIDTH, HEIGHT);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+        Ok(png)
+    }
+}
+
+// Rows of a glyph from the top, the leftmost pixel in the highest of five
+// bits. Characters without one are drawn as `?`.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '\'' => [0x0c, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x00, 0x00, 0x04],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
@@ -0,0 +1,917 @@
+use crate::{
+    answers::{Moderation, SharedAnswer},
+    ballots::SharedBallot,
+    config::Config,
+    crdt::PnCounter,
+    error::AppError,
+    poll::{unix_now_millis, PollDefinition},
+    quiz::SavedStanding,
+    state::AppState,
+    webhooks::Subscription,
+    websocket::{
+        announce_transition, broadcast_answer, broadcast_poll, broadcast_retraction, controls,
+        merge_controls, Controls,
+    },
+};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, sleep},
+};
+use tracing::{debug, info, warn};
+
+// How often changed counters are shared, which bounds how far behind other
+// replicas' sockets run.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+
+// Everything is shared again this often, so replicas that missed gossip or
+// joined late catch up.
+const ANTI_ENTROPY_TICKS: u32 = 25;
+
+// Most ballots, answers or standings sent in one message of a full sync.
+const SHARE_CHUNK: usize = 500;
+
+// Signed gossip older or newer than this is refused, so a recorded frame
+// cannot be replayed later, e.g. to keep a dead leader's heartbeat going.
+const MAX_GOSSIP_AGE: u64 = 30_000;
+
+const PEER_REFRESH: Duration = Duration::from_secs(5);
+
+const REDIS_CHANNEL: &str = "gossip";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    // A cluster of one: nothing leaves the process.
+    Memory,
+    Tcp,
+    Redis,
+}
+
+impl FromStr for TransportKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(Self::Memory),
+            "tcp" => Ok(Self::Tcp),
+            "redis" => Ok(Self::Redis),
+            _ => Err(()),
+        }
+    }
+}
+
+// Everything replicas gossip is state rather than commands or deltas, so lost,
+// late or repeated messages do no harm and the next full sync repairs gaps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Gossip {
+    // Everything one replica knows about the counters of a poll whose
+    // ballots can't be amended. Polls with a ledger count its ballots.
+    Counts {
+        poll: usize,
+        counters: PnCounter,
+    },
+    // Standing ballots and retractions of a poll's ledger.
+    Ballots {
+        poll: usize,
+        ballots: Vec<SharedBallot>,
+    },
+    // Text answers along with how they were moderated.
+    Answers {
+        poll: usize,
+        answers: Vec<SharedAnswer>,
+    },
+    // Quiz players and the answers they scored with.
+    Standings {
+        standings: Vec<SavedStanding>,
+    },
+    // Where the presenter has the session and which polls are locked or
+    // hidden.
+    Controls {
+        controls: Controls,
+    },
+    // The leader announced the current poll's scheduled opening or closing.
+    Transition,
+    // Liveness for the peer leader election.
+    Heartbeat,
+    // A poll added to the session on another replica, as its poll `poll`.
+    Created {
+        poll: usize,
+        definition: PollDefinition,
+    },
+    // Webhook subscriptions made or removed on another replica.
+    Subscribed {
+        subscription: Subscription,
+    },
+    Unsubscribed {
+        id: String,
+    },
+    // Asks every replica for a full sync, e.g. after missing gossip.
+    Resync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: String,
+    // Unix milliseconds.
+    pub sent_at: u64,
+    #[serde(flatten)]
+    pub gossip: Gossip,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs gossip leaving the process and checks gossip coming in with the
+/// cluster secret, so only replicas holding it can take part. Lines are the
+/// hex HMAC-SHA256 of the JSON, a space, then the JSON.
+#[derive(Clone)]
+pub struct Seal(HmacSha256);
+
+impl Seal {
+    pub fn new(secret: &str) -> Self {
+        Self(HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size"))
+    }
+
+    fn seal(&self, envelope: &Envelope) -> Option<Arc<str>> {
+        let json = serde_json::to_string(envelope)
+            .inspect_err(|e| warn!("Failed to serialize gossip: {}", e))
+            .ok()?;
+        let mut mac = self.0.clone();
+        mac.update(json.as_bytes());
+        Some(format!("{} {}", hex::encode(mac.finalize().into_bytes()), json).into())
+    }
+
+    fn open(&self, line: &str) -> Result<Envelope, &'static str> {
+        let (signature, json) = line.split_once(' ').ok_or("unsigned")?;
+        let signature = hex::decode(signature).map_err(|_| "unsigned")?;
+        let mut mac = self.0.clone();
+        mac.update(json.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "bad signature")?;
+        let envelope: Envelope = serde_json::from_str(json).map_err(|_| "malformed")?;
+        if unix_now_millis().abs_diff(envelope.sent_at) > MAX_GOSSIP_AGE {
+            return Err("stale");
+        }
+        Ok(envelope)
+    }
+}
+
+/// Carries gossip between replicas. Delivery is best effort; replicas keep
+/// sharing their state until they agree.
+pub trait Transport: Send + Sync {
+    fn publish(&self, envelope: Envelope);
+    fn subscribe(&self) -> broadcast::Receiver<Envelope>;
+}
+
+/// Replicas sharing one process, e.g. a single node or a local test.
+#[derive(Clone)]
+pub struct InProcess {
+    tx: broadcast::Sender<Envelope>,
+}
+
+impl Default for InProcess {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(100).0,
+        }
+    }
+}
+
+impl Transport for InProcess {
+    fn publish(&self, envelope: Envelope) {
+        // Nobody listening is fine.
+        let _ = self.tx.send(envelope);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.tx.subscribe()
+    }
+}
+
+/// Signed newline-delimited JSON over TCP. Every replica listens for peers
+/// and dials every address its peer names resolve to, so a swarm service
+/// name like `tasks.rust` finds every replica, including new ones.
+pub struct Tcp {
+    outbound: broadcast::Sender<Arc<str>>,
+    inbound: broadcast::Sender<Envelope>,
+    seal: Seal,
+}
+
+impl Tcp {
+    pub async fn start(port: u16, peers: Vec<String>, seal: Seal) -> Result<Self, AppError> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        info!("Cluster listening on port {}", port);
+        Ok(Self::listen(listener, peers, seal))
+    }
+
+    pub fn listen(listener: TcpListener, peers: Vec<String>, seal: Seal) -> Self {
+        let outbound = broadcast::channel(100).0;
+        let inbound = broadcast::channel(100).0;
+
+        let inbound_clone = inbound.clone();
+        let seal_clone = seal.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        debug!("Peer {} connected", addr);
+                        tokio::spawn(read_peer(
+                            stream,
+                            addr,
+                            inbound_clone.clone(),
+                            seal_clone.clone(),
+                        ));
+                    }
+                    Err(e) => warn!("Failed to accept peer: {}", e),
+                }
+            }
+        });
+
+        let outbound_clone = outbound.clone();
+        tokio::spawn(dial_peers(peers, outbound_clone));
+
+        Self {
+            outbound,
+            inbound,
+            seal,
+        }
+    }
+}
+
+impl Transport for Tcp {
+    fn publish(&self, envelope: Envelope) {
+        if let Some(line) = self.seal.seal(&envelope) {
+            let _ = self.outbound.send(line);
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.inbound.subscribe()
+    }
+}
+
+async fn read_peer(
+    stream: TcpStream,
+    addr: SocketAddr,
+    inbound: broadcast::Sender<Envelope>,
+    seal: Seal,
+) {
+    let mut lines = BufReader::new(stream).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match seal.open(&line) {
+                Ok(envelope) => {
+                    let _ = inbound.send(envelope);
+                }
+                // A clock running off only costs the frame; a peer without
+                // the secret is hung up on.
+                Err(reason @ "stale") => warn!("Refused gossip from {}: {}", addr, reason),
+                Err(reason) => {
+                    warn!("Refused gossip from {}: {}", addr, reason);
+                    break;
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Lost peer {}: {}", addr, e);
+                break;
+            }
+        }
+    }
+    debug!("Peer {} disconnected", addr);
+}
+
+// Resolves the peer names again every few seconds and dials any address not
+// already connected.
+async fn dial_peers(peers: Vec<String>, outbound: broadcast::Sender<Arc<str>>) {
+    let connected: Arc<Mutex<HashSet<SocketAddr>>> = Arc::default();
+    loop {
+        for peer in &peers {
+            let addrs = match lookup_host(peer.as_str()).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    debug!("Failed to resolve peer {}: {}", peer, e);
+                    continue;
+                }
+            };
+            for addr in addrs {
+                let new = connected
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(addr);
+                if new {
+                    let connected = Arc::clone(&connected);
+                    let rx = outbound.subscribe();
+                    tokio::spawn(async move {
+                        write_peer(addr, rx).await;
+                        connected
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .remove(&addr);
+                    });
+                }
+            }
+        }
+        sleep(PEER_REFRESH).await;
+    }
+}
+
+async fn write_peer(addr: SocketAddr, mut rx: broadcast::Receiver<Arc<str>>) {
+    let mut stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("Failed to dial peer {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Dialed peer {}", addr);
+
+    loop {
+        let line = match rx.recv().await {
+            Ok(line) => line,
+            // The peer catches up with the next full sync.
+            Err(RecvError::Lagged(skipped)) => {
+                debug!("Skipped {} gossip messages to {}", skipped, addr);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let written = async {
+            stream.write_all(line.as_bytes()).await?;
+            stream.write_all(b"\n").await
+        };
+        if let Err(e) = written.await {
+            warn!("Lost peer {}: {}", addr, e);
+            return;
+        }
+    }
+}
+
+/// Signed gossip over a Redis pub/sub channel, for deployments that already
+/// run Redis instead of letting replicas dial each other. Only gossip goes
+/// through Redis: client frames are not relayed, since every replica builds
+/// its own sockets' frames from the state it merged, and what a socket is
+/// shown depends on whether it presents.
+pub struct RedisPubSub {
+    outbound: broadcast::Sender<Arc<str>>,
+    inbound: broadcast::Sender<Envelope>,
+    seal: Seal,
+}
+
+impl RedisPubSub {
+    pub async fn start(url: &str, seal: Seal) -> Result<Self, AppError> {
+        let client = redis::Client::open(url)?;
+        let mut connection = client.get_multiplexed_async_connection().await?;
+        info!("Cluster gossiping over Redis channel {}", REDIS_CHANNEL);
+
+        let outbound = broadcast::channel::<Arc<str>>(100).0;
+        let inbound = broadcast::channel(100).0;
+
+        let mut rx = outbound.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let line = match rx.recv().await {
+                    Ok(line) => line,
+                    // Peers catch up with the next full sync.
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Skipped publishing {} gossip messages", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let published: redis::RedisResult<()> =
+                    connection.publish(REDIS_CHANNEL, &*line).await;
+                if let Err(e) = published {
+                    warn!("Failed to publish gossip: {}", e);
+                }
+            }
+        });
+
+        let inbound_clone = inbound.clone();
+        let seal_clone = seal.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = subscribe(&client, &inbound_clone, &seal_clone).await {
+                    warn!("Lost Redis subscription: {}", e);
+                }
+                sleep(PEER_REFRESH).await;
+            }
+        });
+
+        Ok(Self {
+            outbound,
+            inbound,
+            seal,
+        })
+    }
+}
+
+async fn subscribe(
+    client: &redis::Client,
+    inbound: &broadcast::Sender<Envelope>,
+    seal: &Seal,
+) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(REDIS_CHANNEL).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match seal.open(&payload) {
+            Ok(envelope) => {
+                let _ = inbound.send(envelope);
+            }
+            Err(reason) => warn!("Refused gossip from Redis: {}", reason),
+        }
+    }
+    Ok(())
+}
+
+impl Transport for RedisPubSub {
+    fn publish(&self, envelope: Envelope) {
+        if let Some(line) = self.seal.seal(&envelope) {
+            let _ = self.outbound.send(line);
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.inbound.subscribe()
+    }
+}
+
+/// What the polls of this replica need to know about it: its name, which
+/// settles concurrent writes, the node its counts are kept under, and the
+/// key voter ids are turned into
+/// pseudonyms with. With the cluster secret as key every replica derives
+/// the same pseudonym for a voter, so anonymous ballots can be amended on
+/// any of them.
+#[derive(Clone)]
+pub struct Replica {
+    pub name: String,
+    // The replica name plus when this process started. Counts are kept per
+    // run, so a restarted replica never counts a vote its earlier run, the
+    // save file or its peers already hold.
+    pub node: String,
+    pseudonyms: HmacSha256,
+}
+
+impl Replica {
+    pub fn new(config: &Config) -> Self {
+        // A lone replica has nobody to agree with.
+        let key = match &config.cluster_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => uuid::Uuid::new_v4().as_bytes().to_vec(),
+        };
+        Self {
+            name: config.cluster_node.clone(),
+            node: format!("{}/{}", config.cluster_node, unix_now_millis()),
+            pseudonyms: HmacSha256::new_from_slice(&key).expect("HMAC takes keys of any size"),
+        }
+    }
+
+    /// A keyed digest of `voter`. The prefix keeps pseudonyms from ever
+    /// doubling as gossip signatures.
+    pub fn digest(&self, voter: &str) -> [u8; 32] {
+        let mut mac = self.pseudonyms.clone();
+        mac.update(b"voter:");
+        mac.update(voter.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    pub fn pseudonym(&self, voter: &str) -> String {
+        hex::encode(&self.digest(voter)[..16])
+    }
+}
+
+/// This replica's name and the transport it gossips over.
+pub struct Cluster {
+    // The replica's node, see `Replica::node`.
+    pub node: String,
+    transport: Box<dyn Transport>,
+    // Set when a peer asked for a full sync.
+    resync: AtomicBool,
+}
+
+impl Cluster {
+    pub fn new(node: &str, transport: Box<dyn Transport>) -> Self {
+        Self {
+            node: node.to_string(),
+            transport,
+            resync: AtomicBool::new(false),
+        }
+    }
+
+    pub fn publish(&self, gossip: Gossip) {
+        self.transport.publish(Envelope {
+            from: self.node.clone(),
+            sent_at: unix_now_millis(),
+            gossip,
+        });
+    }
+}
+
+/// Shares this replica's state and applies what the others share.
+pub fn start(state: &Arc<AppState>) {
+    let mut rx = state.cluster.transport.subscribe();
+    let state_clone = Arc::clone(state);
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(envelope) => receive(&state_clone, envelope),
+                // Whatever was skipped, commands included, comes back with
+                // full syncs.
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Skipped {} gossip messages, asking for a full sync",
+                        skipped
+                    );
+                    state_clone.cluster.publish(Gossip::Resync);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let state_clone = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut shared = Vec::new();
+        let mut interval = interval(GOSSIP_INTERVAL);
+        let mut tick = 0u32;
+        loop {
+            interval.tick().await;
+            tick = tick.wrapping_add(1);
+            let full = tick.is_multiple_of(ANTI_ENTROPY_TICKS)
+                || state_clone.cluster.resync.swap(false, Relaxed);
+            share(&state_clone, &mut shared, full);
+        }
+    });
+}
+
+// Shares what changed here since the last tick, or everything on a full
+// sync. `shared` remembers the counters last sent for each poll.
+fn share(state: &AppState, shared: &mut Vec<Option<PnCounter>>, full: bool) {
+    let cluster = &state.cluster;
+    if full {
+        // Created polls go first, so the controls never point past them.
+        let first = state.session.created_from();
+        for (offset, definition) in state.session.created().into_iter().enumerate() {
+            cluster.publish(Gossip::Created {
+                poll: first + offset,
+                definition,
+            });
+        }
+        cluster.publish(Gossip::Controls {
+            controls: controls(state),
+        });
+        for subscription in state.webhooks.saved() {
+            cluster.publish(Gossip::Subscribed { subscription });
+        }
+        for id in state.webhooks.removed() {
+            cluster.publish(Gossip::Unsubscribed { id });
+        }
+    }
+
+    // Polls may have been created since the last tick.
+    let polls = state.session.polls();
+    shared.resize(polls.len(), None);
+    for (index, poll) in polls.iter().enumerate() {
+        if poll.ballots.amendable() {
+            for ballots in chunks(poll.ballots.share(full)) {
+                cluster.publish(Gossip::Ballots {
+                    poll: index,
+                    ballots,
+                });
+            }
+        } else {
+            let counters = poll.counters.state(&cluster.node);
+            if full || shared[index].as_ref() != Some(&counters) {
+                cluster.publish(Gossip::Counts {
+                    poll: index,
+                    counters: counters.clone(),
+                });
+                shared[index] = Some(counters);
+            }
+        }
+        for answers in chunks(poll.answers.share(full)) {
+            cluster.publish(Gossip::Answers {
+                poll: index,
+                answers,
+            });
+        }
+    }
+    for standings in chunks(state.quiz.share(full)) {
+        cluster.publish(Gossip::Standings { standings });
+    }
+}
+
+// Splits a full sync of a big poll into several messages rather than one
+// huge line.
+fn chunks<T>(mut items: Vec<T>) -> impl Iterator<Item = Vec<T>> {
+    std::iter::from_fn(move || {
+        (!items.is_empty()).then(|| {
+            let rest = items.split_off(items.len().min(SHARE_CHUNK));
+            std::mem::replace(&mut items, rest)
+        })
+    })
+}
+
+fn receive(state: &Arc<AppState>, envelope: Envelope) {
+    // Transports may hand a replica its own gossip back.
+    if envelope.from == state.cluster.node {
+        return;
+    }
+    state.leader.observe(&envelope.from);
+
+    match envelope.gossip {
+        Gossip::Counts {
+            poll: index,
+            counters,
+        } => {
+            let Some(poll) = state.session.polls().get(index) else {
+                warn!("Gossip from {} for unknown poll {}", envelope.from, index);
+                return;
+            };
+            // A poll with a ledger counts the ballots in it instead.
+            if poll.ballots.amendable() {
+                return;
+            }
+            // Merged counts reach sockets with the next batch of updates.
+            poll.counters.merge(&state.cluster.node, &counters);
+        }
+        Gossip::Ballots {
+            poll: index,
+            ballots,
+        } => {
+            let Some(poll) = state.session.polls().get(index) else {
+                warn!("Gossip from {} for unknown poll {}", envelope.from, index);
+                return;
+            };
+            poll.ballots.merge(ballots, &*poll.counters);
+        }
+        Gossip::Answers {
+            poll: index,
+            answers,
+        } => {
+            let Some(poll) = state.session.polls().get(index) else {
+                warn!("Gossip from {} for unknown poll {}", envelope.from, index);
+                return;
+            };
+            let current = index == state.session.index();
+            for answer in poll.answers.merge(answers) {
+                if answer.moderation == Moderation::Approved {
+                    poll.wordcloud.add(&answer.text);
+                    if current {
+                        broadcast_answer(state, &answer);
+                    }
+                } else {
+                    poll.wordcloud.remove(&answer.text);
+                    if current {
+                        broadcast_retraction(state, &answer);
+                    }
+                }
+            }
+        }
+        Gossip::Standings { standings } => state.quiz.merge(standings),
+        Gossip::Controls { controls } => {
+            merge_controls(state, &controls);
+            // Catches up on a transition whose announcement was missed.
+            announce_transition(state);
+        }
+        Gossip::Transition => {
+            announce_transition(state);
+        }
+        Gossip::Heartbeat => {}
+        // Full syncs repeat every created poll.
+        Gossip::Created { poll: index, .. } if index < state.session.polls().len() => {}
+        Gossip::Created {
+            poll: index,
+            definition,
+        } => match state.session.replicate(index, definition, &state.config) {
+            Ok(_) => broadcast_poll(state),
+            Err(e) => warn!("Poll created on {} not added: {}", envelope.from, e),
+        },
+        Gossip::Subscribed { subscription } => state.webhooks.subscribe(subscription),
+        Gossip::Unsubscribed { id } => {
+            state.webhooks.unsubscribe(&id);
+        }
+        Gossip::Resync => state.cluster.resync.store(true, Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::Events,
+        leader::{Leader, Single},
+        metrics::Metrics,
+        poll::BallotMode,
+        quiz::Quiz,
+        session::Session,
+        store::Store,
+        throttle::Throttle,
+        webhooks::Webhooks,
+        websocket::{control, process_request, Command},
+    };
+    use std::{net::Ipv4Addr, sync::atomic::AtomicUsize};
+    use tokio::{io::AsyncReadExt, time::timeout};
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    async fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+        let waited = timeout(PATIENCE, async {
+            while !done() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_ok(), "Timed out waiting until {what}");
+    }
+
+    async fn next_from(rx: &mut broadcast::Receiver<Envelope>, from: &str) -> Envelope {
+        loop {
+            match rx.recv().await {
+                Ok(envelope) if envelope.from == from => return envelope,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => panic!("Transport closed"),
+            }
+        }
+    }
+
+    fn envelope(from: &str, sent_at: u64, gossip: Gossip) -> Envelope {
+        Envelope {
+            from: from.into(),
+            sent_at,
+            gossip,
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_only_takes_fresh_gossip_signed_with_the_secret() {
+        let a_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let b_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a_addr = a_listener.local_addr().unwrap();
+        let b_addr = b_listener.local_addr().unwrap();
+        let a = Tcp::listen(a_listener, vec![b_addr.to_string()], Seal::new("secret"));
+        let b = Tcp::listen(b_listener, vec![a_addr.to_string()], Seal::new("secret"));
+
+        let mut rx = b.subscribe();
+        let arrived = timeout(PATIENCE, async {
+            loop {
+                a.publish(envelope("a", unix_now_millis(), Gossip::Heartbeat));
+                if let Ok(envelope) =
+                    timeout(Duration::from_millis(100), next_from(&mut rx, "a")).await
+                {
+                    return envelope;
+                }
+            }
+        })
+        .await
+        .expect("Gossip from a reached b");
+        assert!(matches!(arrived.gossip, Gossip::Heartbeat));
+
+        let forged = Seal::new("guess")
+            .seal(&envelope("raw", unix_now_millis(), Gossip::Resync))
+            .unwrap();
+        let stale = Seal::new("secret")
+            .seal(&envelope("raw", 0, Gossip::Resync))
+            .unwrap();
+        let unsigned =
+            serde_json::to_string(&envelope("raw", unix_now_millis(), Gossip::Resync)).unwrap();
+        let good = Seal::new("secret")
+            .seal(&envelope("raw", unix_now_millis(), Gossip::Transition))
+            .unwrap();
+        // A peer without the secret is hung up on.
+        for line in [unsigned.as_str(), &forged] {
+            let mut raw = TcpStream::connect(b_addr).await.unwrap();
+            raw.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+            let mut rest = Vec::new();
+            let read = timeout(PATIENCE, raw.read_to_end(&mut rest)).await;
+            assert!(matches!(read, Ok(Ok(0))), "b hung up on {line}");
+        }
+
+        let mut raw = TcpStream::connect(b_addr).await.unwrap();
+        for line in [&stale, &good] {
+            raw.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+        }
+
+        // Lines are read in order, so anything refused would arrive first.
+        let received = timeout(PATIENCE, next_from(&mut rx, "raw"))
+            .await
+            .expect("Signed gossip reached b");
+        assert!(matches!(received.gossip, Gossip::Transition));
+    }
+
+    #[tokio::test]
+    async fn redis_carries_gossip_signed_with_the_secret() {
+        let url = crate::redis_standin::start().await;
+        let a = RedisPubSub::start(&url, Seal::new("secret")).await.unwrap();
+        let b = RedisPubSub::start(&url, Seal::new("secret")).await.unwrap();
+        let outsider = RedisPubSub::start(&url, Seal::new("guess")).await.unwrap();
+
+        let mut rx = b.subscribe();
+        let arrived = timeout(PATIENCE, async {
+            loop {
+                outsider.publish(envelope("outsider", unix_now_millis(), Gossip::Resync));
+                a.publish(envelope("a", unix_now_millis(), Gossip::Heartbeat));
+                match timeout(Duration::from_millis(100), rx.recv()).await {
+                    Ok(Ok(envelope)) if envelope.from != "b" => return envelope,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("Gossip from a reached b");
+        assert_eq!(arrived.from, "a");
+    }
+
+    fn replica(node: &str, transport: &InProcess) -> Arc<AppState> {
+        let mut config = Config::test(node);
+        config.poll_ballot = BallotMode::Ranked;
+        let session = Session::load(&config, Store::Atomic).unwrap();
+        let cluster = Cluster::new(&session.replica().node, Box::new(transport.clone()));
+        let state = Arc::new(AppState {
+            quiz: Quiz::new(session.replica().clone()),
+            session,
+            concurrent_users: AtomicUsize::new(0),
+            total_users: AtomicUsize::new(0),
+            broadcast_tx: broadcast::channel(100).0,
+            presenter_tx: broadcast::channel(100).0,
+            metrics: Metrics::default(),
+            cluster,
+            leader: Leader::new(Box::new(Single)),
+            events: Events::default(),
+            webhooks: Webhooks::new(config.webhook_key.as_deref()),
+            throttle: Throttle::new(&config),
+            config,
+        });
+        start(&state);
+        state
+    }
+
+    async fn vote(state: &Arc<AppState>, voter: &str, message: &str) {
+        process_request(
+            message,
+            Some(voter.into()),
+            None,
+            false,
+            Ipv4Addr::LOCALHOST.into(),
+            state,
+        )
+        .await;
+    }
+
+    fn tallies(state: &AppState) -> (usize, Option<&'static str>) {
+        let poll = state.session.current();
+        (
+            poll.counters.snapshot().ballots,
+            poll.ballots.runoff().winner,
+        )
+    }
+
+    #[tokio::test]
+    async fn replicas_agree_on_amended_ballots_and_controls() {
+        let transport = InProcess::default();
+        let a = replica("a", &transport);
+        let b = replica("b", &transport);
+
+        vote(&a, "ann", r#"{"type":"ranked","ranking":["red","green"]}"#).await;
+        vote(&b, "bob", r#"{"type":"ranked","ranking":["blue","red"]}"#).await;
+        vote(&b, "cat", r#"{"type":"ranked","ranking":["green"]}"#).await;
+        eventually("both replicas count three ballots", || {
+            tallies(&a).0 == 3 && tallies(&b).0 == 3
+        })
+        .await;
+
+        // Ann changes her ballot on b and Cat retracts hers on a.
+        vote(&b, "ann", r#"{"type":"change","to":["blue"]}"#).await;
+        vote(&a, "cat", r#"{"type":"retract"}"#).await;
+        eventually("both replicas count the amended ballots", || {
+            tallies(&a) == (2, Some("blue")) && tallies(&b) == (2, Some("blue"))
+        })
+        .await;
+        assert_eq!(
+            a.session.current().counters.snapshot(),
+            b.session.current().counters.snapshot()
+        );
+
+        control(&a, Command::Lock).unwrap();
+        eventually("b is locked", || b.session.current().locked.is_on()).await;
+        control(&b, Command::Unlock).unwrap();
+        eventually("a is unlocked", || !a.session.current().locked.is_on()).await;
+    }
+}
@@ -1,13 +1,68 @@
-use crate::error::AppError;
+use crate::{
+    cluster::TransportKind,
+    counters::COLORS,
+    error::AppError,
+    leader::ElectionKind,
+    poll::{BallotMode, PollDefinition, Privacy, Visibility},
+    store::StoreKind,
+};
 use tracing::{info, warn};
+use uuid::Uuid;
 
+// Plain text frame limit for polls voting on fixed options; text polls use
+// their own configured limit.
 pub const MAX_BYTES: u8 = 10;
+pub const MAX_BALLOT_BYTES: u16 = 256;
+pub const MAX_VOTER_BYTES: u8 = 64;
+pub const MAX_NAME_BYTES: u8 = 32;
+// Longest a quiz question can run.
+pub const MAX_QUIZ_SECONDS: u64 = 60 * 60;
+// Answers waiting for moderation, per poll and per voter.
+pub const MAX_PENDING_ANSWERS: u16 = 500;
+pub const MAX_PENDING_PER_VOTER: u8 = 3;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub rust_port: u16,
     pub svelte_url: String,
     pub state_path: String,
+    pub outbound_high_water: usize,
+    // Messages per second a voter, and a client address, may send; bursts
+    // of a few seconds' worth go through.
+    pub voter_rate: u32,
+    pub address_rate: u32,
+    pub poll_ballot: BallotMode,
+    pub poll_results: Visibility,
+    pub poll_privacy: Privacy,
+    pub poll_max_choices: usize,
+    pub poll_max_score: usize,
+    pub poll_max_text_bytes: usize,
+    pub wordcloud_top_k: usize,
+    pub quiz_correct: usize,
+    pub quiz_seconds: u64,
+    pub poll_opens_at: Option<u64>,
+    pub poll_closes_at: Option<u64>,
+    // Room for polls created while the session runs, on top of those it
+    // starts with.
+    pub created_polls: usize,
+    pub session_path: Option<String>,
+    pub admin_token: Option<String>,
+    // Encrypts webhook secrets in saves and gossip; every replica needs the
+    // same.
+    pub webhook_key: Option<String>,
+    pub cluster_transport: TransportKind,
+    pub cluster_node: String,
+    pub cluster_port: u16,
+    pub cluster_peers: Vec<String>,
+    // Signs gossip and keys voter pseudonyms; every replica needs the same.
+    pub cluster_secret: Option<String>,
+    pub counter_backend: StoreKind,
+    pub redis_url: String,
+    pub leader_election: ElectionKind,
+    pub leader_lease_path: String,
+    pub api_origins: Vec<String>,
+    // Where this backend is reached from outside, for absolute embed links.
+    pub public_url: String,
 }
 
 impl Config {
@@ -32,12 +87,438 @@ impl Config {
             })
             .unwrap_or_else(|_| "/saved_state.json".into());
 
+        let outbound_high_water = var("RUST_OUTBOUND_HIGH_WATER")
+            .inspect_err(|_| {
+                info!("RUST_OUTBOUND_HIGH_WATER not set, using default");
+            })
+            .unwrap_or_else(|_| "256".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_OUTBOUND_HIGH_WATER value".into()))?;
+
+        if outbound_high_water == 0 {
+            return Err(AppError::Config(
+                "RUST_OUTBOUND_HIGH_WATER must be greater than 0".into(),
+            ));
+        }
+
+        let voter_rate = var("RUST_VOTER_RATE")
+            .inspect_err(|_| {
+                info!("RUST_VOTER_RATE not set, using default");
+            })
+            .unwrap_or_else(|_| "2".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_VOTER_RATE value".into()))?;
+
+        if voter_rate == 0 {
+            return Err(AppError::Config(
+                "RUST_VOTER_RATE must be greater than 0".into(),
+            ));
+        }
+
+        // A whole audience may share one address behind a venue's NAT.
+        let address_rate = var("RUST_ADDRESS_RATE")
+            .inspect_err(|_| {
+                info!("RUST_ADDRESS_RATE not set, using default");
+            })
+            .unwrap_or_else(|_| "100".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_ADDRESS_RATE value".into()))?;
+
+        if address_rate == 0 {
+            return Err(AppError::Config(
+                "RUST_ADDRESS_RATE must be greater than 0".into(),
+            ));
+        }
+
+        let poll_ballot = var("RUST_POLL_BALLOT")
+            .inspect_err(|_| {
+                info!("RUST_POLL_BALLOT not set, using default");
+            })
+            .unwrap_or_else(|_| "single".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_BALLOT value".into()))?;
+
+        let poll_results = var("RUST_POLL_RESULTS")
+            .inspect_err(|_| {
+                info!("RUST_POLL_RESULTS not set, using default");
+            })
+            .unwrap_or_else(|_| "live".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_RESULTS value".into()))?;
+
+        let poll_privacy = var("RUST_POLL_PRIVACY")
+            .inspect_err(|_| {
+                info!("RUST_POLL_PRIVACY not set, using default");
+            })
+            .unwrap_or_else(|_| "anonymous".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_PRIVACY value".into()))?;
+
+        let poll_max_choices = var("RUST_POLL_MAX_CHOICES")
+            .inspect_err(|_| {
+                info!("RUST_POLL_MAX_CHOICES not set, using default");
+            })
+            .unwrap_or_else(|_| "2".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_MAX_CHOICES value".into()))?;
+
+        if poll_max_choices == 0 {
+            return Err(AppError::Config(
+                "RUST_POLL_MAX_CHOICES must be greater than 0".into(),
+            ));
+        }
+
+        let poll_max_score = var("RUST_POLL_MAX_SCORE")
+            .inspect_err(|_| {
+                info!("RUST_POLL_MAX_SCORE not set, using default");
+            })
+            .unwrap_or_else(|_| "5".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_MAX_SCORE value".into()))?;
+
+        if poll_max_score == 0 {
+            return Err(AppError::Config(
+                "RUST_POLL_MAX_SCORE must be greater than 0".into(),
+            ));
+        }
+
+        let poll_max_text_bytes = var("RUST_POLL_MAX_TEXT_BYTES")
+            .inspect_err(|_| {
+                info!("RUST_POLL_MAX_TEXT_BYTES not set, using default");
+            })
+            .unwrap_or_else(|_| "140".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_MAX_TEXT_BYTES value".into()))?;
+
+        if poll_max_text_bytes == 0 {
+            return Err(AppError::Config(
+                "RUST_POLL_MAX_TEXT_BYTES must be greater than 0".into(),
+            ));
+        }
+
+        let wordcloud_top_k = var("RUST_WORDCLOUD_TOP_K")
+            .inspect_err(|_| {
+                info!("RUST_WORDCLOUD_TOP_K not set, using default");
+            })
+            .unwrap_or_else(|_| "50".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_WORDCLOUD_TOP_K value".into()))?;
+
+        if wordcloud_top_k == 0 {
+            return Err(AppError::Config(
+                "RUST_WORDCLOUD_TOP_K must be greater than 0".into(),
+            ));
+        }
+
+        let quiz_correct = var("RUST_QUIZ_CORRECT")
+            .inspect_err(|_| {
+                info!("RUST_QUIZ_CORRECT not set, using default");
+            })
+            .unwrap_or_else(|_| COLORS[0].into());
+        let quiz_correct = COLORS
+            .iter()
+            .position(|color| *color == quiz_correct)
+            .ok_or_else(|| AppError::Config("Invalid RUST_QUIZ_CORRECT value".into()))?;
+
+        let quiz_seconds = var("RUST_QUIZ_SECONDS")
+            .inspect_err(|_| {
+                info!("RUST_QUIZ_SECONDS not set, using default");
+            })
+            .unwrap_or_else(|_| "20".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_QUIZ_SECONDS value".into()))?;
+
+        if quiz_seconds == 0 || quiz_seconds > MAX_QUIZ_SECONDS {
+            return Err(AppError::Config(format!(
+                "RUST_QUIZ_SECONDS must be between 1 and {MAX_QUIZ_SECONDS}"
+            )));
+        }
+
+        let poll_opens_at = var("RUST_POLL_OPENS_AT")
+            .inspect_err(|_| {
+                info!("RUST_POLL_OPENS_AT not set, poll opens immediately");
+            })
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse())
+            .transpose()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_OPENS_AT value".into()))?;
+
+        let poll_closes_at = var("RUST_POLL_CLOSES_AT")
+            .inspect_err(|_| {
+                info!("RUST_POLL_CLOSES_AT not set, poll never closes");
+            })
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse())
+            .transpose()
+            .map_err(|_| AppError::Config("Invalid RUST_POLL_CLOSES_AT value".into()))?;
+
+        if let (Some(opens_at), Some(closes_at)) = (poll_opens_at, poll_closes_at) {
+            if opens_at >= closes_at {
+                return Err(AppError::Config(
+                    "RUST_POLL_OPENS_AT must be before RUST_POLL_CLOSES_AT".into(),
+                ));
+            }
+        }
+
+        let created_polls = var("RUST_CREATED_POLLS")
+            .inspect_err(|_| {
+                info!("RUST_CREATED_POLLS not set, using default");
+            })
+            .unwrap_or_else(|_| "32".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_CREATED_POLLS value".into()))?;
+
+        let session_path = var("RUST_SESSION_PATH")
+            .inspect_err(|_| {
+                info!("RUST_SESSION_PATH not set, running a single poll");
+            })
+            .ok()
+            .filter(|value| !value.is_empty());
+
+        let admin_token = var("RUST_ADMIN_TOKEN")
+            .inspect_err(|_| {
+                info!("RUST_ADMIN_TOKEN not set, admin endpoints are disabled");
+            })
+            .ok()
+            .filter(|value| !value.is_empty());
+
+        let webhook_key = var("RUST_WEBHOOK_KEY")
+            .inspect_err(|_| {
+                info!("RUST_WEBHOOK_KEY not set, webhooks are disabled");
+            })
+            .ok()
+            .filter(|value| !value.is_empty());
+
+        let cluster_transport = var("RUST_CLUSTER_TRANSPORT")
+            .inspect_err(|_| {
+                info!("RUST_CLUSTER_TRANSPORT not set, running a single replica");
+            })
+            .unwrap_or_else(|_| "memory".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_CLUSTER_TRANSPORT value".into()))?;
+
+        // Swarm gives every task its own hostname.
+        let cluster_node = var("RUST_CLUSTER_NODE")
+            .ok()
+            .filter(|value| !value.is_empty())
+            .or_else(|| var("HOSTNAME").ok())
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| {
+                info!("RUST_CLUSTER_NODE not set, using a random name");
+                Uuid::new_v4().to_string()
+            });
+
+        let cluster_port = var("RUST_CLUSTER_PORT")
+            .inspect_err(|_| {
+                info!("RUST_CLUSTER_PORT not set, using default");
+            })
+            .unwrap_or_else(|_| "7946".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_CLUSTER_PORT value".into()))?;
+
+        let cluster_peers: Vec<String> = var("RUST_CLUSTER_PEERS")
+            .inspect_err(|_| {
+                info!("RUST_CLUSTER_PEERS not set, using none");
+            })
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|peer| !peer.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let cluster_secret = var("RUST_CLUSTER_SECRET")
+            .inspect_err(|_| {
+                info!("RUST_CLUSTER_SECRET not set");
+            })
+            .ok()
+            .filter(|value| !value.is_empty());
+
+        let counter_backend = var("RUST_COUNTER_BACKEND")
+            .inspect_err(|_| {
+                info!("RUST_COUNTER_BACKEND not set, using default");
+            })
+            .unwrap_or_else(|_| "atomic".into())
+            .parse()
+            .map_err(|_| AppError::Config("Invalid RUST_COUNTER_BACKEND value".into()))?;
+
+        let redis_url = var("RUST_REDIS_URL")
+            .inspect_err(|_| {
+                info!("RUST_REDIS_URL not set, using default");
+            })
+            .unwrap_or_else(|_| "redis://127.0.0.1:6379".into());
+
+        // A lone replica has nobody to compete with.
+        let leader_election = match var("RUST_LEADER_ELECTION")
+            .ok()
+            .filter(|value| !value.is_empty())
+        {
+            Some(value) => value
+                .parse()
+                .map_err(|_| AppError::Config("Invalid RUST_LEADER_ELECTION value".into()))?,
+            None if cluster_transport == TransportKind::Memory => {
+                info!("RUST_LEADER_ELECTION not set, using single");
+                ElectionKind::Single
+            }
+            None => {
+                info!("RUST_LEADER_ELECTION not set, using peer");
+                ElectionKind::Peer
+            }
+        };
+
+        // No default: the lease only works on a volume every replica mounts,
+        // which the state file alone need not be.
+        let leader_lease_path = match var("RUST_LEADER_LEASE_PATH")
+            .ok()
+            .filter(|value| !value.is_empty())
+        {
+            Some(path) => path,
+            None if leader_election == ElectionKind::File => {
+                return Err(AppError::Config(
+                    "RUST_LEADER_LEASE_PATH must be set for the file election".into(),
+                ))
+            }
+            None => {
+                info!("RUST_LEADER_LEASE_PATH not set, unused by this election");
+                String::new()
+            }
+        };
+
+        let api_origins = var("RUST_API_ORIGINS")
+            .inspect_err(|_| {
+                info!("RUST_API_ORIGINS not set, using none");
+            })
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let public_url = var("RUST_PUBLIC_URL")
+            .ok()
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| {
+                info!("RUST_PUBLIC_URL not set, using default");
+                format!("http://localhost:{rust_port}")
+            })
+            .trim_end_matches('/')
+            .to_string();
+
+        if leader_election == ElectionKind::Peer && cluster_transport == TransportKind::Memory {
+            return Err(AppError::Config(
+                "The peer leader election needs the tcp or redis transport".into(),
+            ));
+        }
+
+        if cluster_transport != TransportKind::Memory && cluster_secret.is_none() {
+            return Err(AppError::Config(
+                "RUST_CLUSTER_SECRET must be set for the tcp and redis transports".into(),
+            ));
+        }
+
+        if cluster_transport == TransportKind::Tcp && cluster_peers.is_empty() {
+            return Err(AppError::Config(
+                "RUST_CLUSTER_PEERS must be set for the tcp transport".into(),
+            ));
+        }
+
         Ok(Self {
             rust_port,
             svelte_url,
             state_path,
+            outbound_high_water,
+            voter_rate,
+            address_rate,
+            poll_ballot,
+            poll_results,
+            poll_privacy,
+            poll_max_choices,
+            poll_max_score,
+            poll_max_text_bytes,
+            wordcloud_top_k,
+            quiz_correct,
+            quiz_seconds,
+            poll_opens_at,
+            poll_closes_at,
+            created_polls,
+            session_path,
+            admin_token,
+            webhook_key,
+            cluster_transport,
+            cluster_node,
+            cluster_port,
+            cluster_peers,
+            cluster_secret,
+            counter_backend,
+            redis_url,
+            leader_election,
+            leader_lease_path,
+            api_origins,
+            public_url,
         })
     }
+
+    /// The poll described by the `RUST_POLL_*` and `RUST_QUIZ_*` settings.
+    pub fn poll_definition(&self) -> PollDefinition {
+        PollDefinition {
+            title: None,
+            ballot: self.poll_ballot,
+            results: self.poll_results,
+            privacy: self.poll_privacy,
+            max_choices: self.poll_max_choices,
+            max_score: self.poll_max_score,
+            max_text_bytes: self.poll_max_text_bytes,
+            correct: self.quiz_correct,
+            seconds: self.quiz_seconds,
+            opens_at: self.poll_opens_at,
+            closes_at: self.poll_closes_at,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A replica named `node` gossiping with the secret "test", without
+    /// reading the environment.
+    pub fn test(node: &str) -> Self {
+        Self {
+            rust_port: 0,
+            svelte_url: "http://localhost:5173".into(),
+            state_path: String::new(),
+            outbound_high_water: 256,
+            voter_rate: 2,
+            address_rate: 100,
+            poll_ballot: BallotMode::Single,
+            poll_results: Visibility::Live,
+            poll_privacy: Privacy::Anonymous,
+            poll_max_choices: 2,
+            poll_max_score: 5,
+            poll_max_text_bytes: 140,
+            wordcloud_top_k: 50,
+            quiz_correct: 0,
+            quiz_seconds: 20,
+            poll_opens_at: None,
+            poll_closes_at: None,
+            created_polls: 4,
+            session_path: None,
+            admin_token: None,
+            webhook_key: Some("test".into()),
+            cluster_transport: TransportKind::Memory,
+            cluster_node: node.into(),
+            cluster_port: 0,
+            cluster_peers: Vec::new(),
+            cluster_secret: Some("test".into()),
+            counter_backend: StoreKind::Atomic,
+            redis_url: String::new(),
+            leader_election: ElectionKind::Single,
+            leader_lease_path: String::new(),
+            api_origins: Vec::new(),
+            public_url: String::new(),
+        }
+    }
 }
 
 fn var(key: &str) -> Result<String, AppError> {
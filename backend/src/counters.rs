@@ -0,0 +1,470 @@
+use crate::crdt::PnCounter;
+use serde::{Deserialize, Serialize};
+use std::{
+    array,
+    collections::BTreeMap,
+    hint::spin_loop,
+    sync::{
+        atomic::{
+            fence, AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release},
+        },
+        Mutex,
+    },
+    thread::{available_parallelism, yield_now},
+};
+
+pub const COLORS: [&str; 4] = ["red", "green", "blue", "purple"];
+
+// Per-option tallies followed by the number of ballots that produced them.
+const SLOTS: usize = COLORS.len() + 1;
+const BALLOTS: usize = COLORS.len();
+
+// Stripes count what was added and what was retracted separately, so both
+// only grow and make up this node's entry of a PN-counter.
+const STRIPE_SLOTS: usize = SLOTS * 2;
+const RETRACTED: usize = SLOTS;
+
+// Every option, as flagged in a stripe's `changed` bits.
+const ALL_CHANGED: usize = (1 << COLORS.len()) - 1;
+
+const SPIN_LIMIT: u32 = 64;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Threads are spread round-robin over the stripes on first use; tokio
+    // workers are long-lived so each keeps hitting the same cache line.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub red: usize,
+    pub green: usize,
+    pub blue: usize,
+    pub purple: usize,
+    pub total: usize,
+    #[serde(default)]
+    pub ballots: usize,
+}
+
+impl Snapshot {
+    pub fn get(&self, color: &str) -> Option<usize> {
+        match color {
+            "red" => Some(self.red),
+            "green" => Some(self.green),
+            "blue" => Some(self.blue),
+            "purple" => Some(self.purple),
+            _ => None,
+        }
+    }
+
+    /// The totals as a single node's entry, for files saved before counts
+    /// were kept per node.
+    pub fn as_node(&self, node: &str) -> PnCounter {
+        // Files saved before ballots were counted hold single-choice votes.
+        let ballots = if self.ballots == 0 {
+            self.red + self.green + self.blue + self.purple
+        } else {
+            self.ballots
+        };
+        let added =
+            [self.red, self.green, self.blue, self.purple, ballots].map(|count| count as u64);
+
+        let mut counter = PnCounter::default();
+        counter.set(node, &added, &[0; SLOTS]);
+        counter
+    }
+
+    /// Mean tally per ballot for every option, e.g. the average score.
+    pub fn averages(&self) -> BTreeMap<&'static str, f64> {
+        COLORS
+            .iter()
+            .map(|color| {
+                let sum = self.get(color).unwrap_or_default();
+                let average = if self.ballots == 0 {
+                    0.0
+                } else {
+                    sum as f64 / self.ballots as f64
+                };
+                (*color, average)
+            })
+            .collect()
+    }
+
+    // Retractions can land on a different stripe than the vote they undo, so
+    // what was added and what was retracted are summed over every stripe
+    // before one is taken from the other. A consistent sweep never retracts
+    // more than was added; the floor at zero only guards against a bug.
+    fn sum(stripes: &[[usize; STRIPE_SLOTS]]) -> Self {
+        let mut sums = [0usize; STRIPE_SLOTS];
+        for counts in stripes {
+            for (sum, count) in sums.iter_mut().zip(counts) {
+                *sum += count;
+            }
+        }
+        let net: [usize; SLOTS] = array::from_fn(|i| sums[i].saturating_sub(sums[RETRACTED + i]));
+        Self {
+            red: net[0],
+            green: net[1],
+            blue: net[2],
+            purple: net[3],
+            total: net[..BALLOTS].iter().sum(),
+            ballots: net[BALLOTS],
+        }
+    }
+}
+
+// One seqlock per stripe: `sequence` is odd while a writer holds the stripe,
+// so it doubles as the writer lock. Aligned to two cache lines to keep the
+// adjacent-line prefetcher from pairing neighbouring stripes. `changed` has
+// a bit for every option written since it was last taken, on the same line
+// so flagging a vote costs nothing extra.
+#[repr(align(128))]
+struct Stripe {
+    sequence: AtomicUsize,
+    counts: [AtomicUsize; STRIPE_SLOTS],
+    changed: AtomicUsize,
+}
+
+impl Stripe {
+    fn new() -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            counts: array::from_fn(|_| AtomicUsize::new(0)),
+            changed: AtomicUsize::new(0),
+        }
+    }
+
+    // Set after the write it flags, so whoever takes the bit also sees it.
+    fn flag(&self, changed: usize) {
+        if changed != 0 {
+            self.changed.fetch_or(changed, Release);
+        }
+    }
+
+    // The counts along with the sequence they were read at.
+    fn read(&self) -> (usize, [usize; STRIPE_SLOTS]) {
+        let mut spins = 0;
+        loop {
+            let before = self.sequence.load(Acquire);
+            if before & 1 == 1 {
+                backoff(&mut spins);
+                continue;
+            }
+
+            let counts = array::from_fn(|i| self.counts[i].load(Relaxed));
+
+            fence(Acquire);
+            if self.sequence.load(Relaxed) == before {
+                return (before, counts);
+            }
+        }
+    }
+
+    fn write(&self, update: impl FnOnce(&[AtomicUsize; STRIPE_SLOTS])) {
+        let mut spins = 0;
+        let mut sequence = self.sequence.load(Relaxed);
+        loop {
+            if sequence & 1 == 1 {
+                backoff(&mut spins);
+                sequence = self.sequence.load(Relaxed);
+                continue;
+            }
+            match self.sequence.compare_exchange_weak(
+                sequence,
+                sequence.wrapping_add(1),
+                Acquire,
+                Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => sequence = current,
+            }
+        }
+        fence(Release);
+
+        update(&self.counts);
+
+        self.sequence.store(sequence.wrapping_add(2), Release);
+    }
+}
+
+// Reads every stripe as they all stood at one moment, so a retraction is
+// never counted without the vote it undoes on another stripe. A sweep is
+// repeated until no stripe was written between reading it and the end of the
+// sweep; writers never wait on it.
+fn sweep<'a>(stripes: impl Iterator<Item = &'a Stripe> + Clone) -> Vec<[usize; STRIPE_SLOTS]> {
+    let mut spins = 0;
+    loop {
+        let reads: Vec<_> = stripes.clone().map(Stripe::read).collect();
+        fence(Acquire);
+        if stripes
+            .clone()
+            .zip(&reads)
+            .all(|(stripe, (sequence, _))| stripe.sequence.load(Relaxed) == *sequence)
+        {
+            return reads.into_iter().map(|(_, counts)| counts).collect();
+        }
+        backoff(&mut spins);
+    }
+}
+
+// A writer preempted mid-update would otherwise leave everyone else spinning
+// for its whole time slice.
+fn backoff(spins: &mut u32) {
+    if *spins < SPIN_LIMIT {
+        *spins += 1;
+        spin_loop();
+    } else {
+        yield_now();
+    }
+}
+
+// Votes land on the calling thread's stripe and never read the others;
+// snapshots sum every stripe, so they are only taken when totals are needed,
+// e.g. once per broadcast. All stripes are read as of one moment, so every
+// ballot in a snapshot is counted whole or not at all. The stripes hold this
+// node's entry of the counters' CRDT state, keeping votes lock-free; every
+// other node's entry, past runs of this process included, is summed into one
+// more stripe.
+pub struct Counters {
+    stripes: Box<[Stripe]>,
+    remote: Stripe,
+    nodes: Mutex<PnCounter>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        let stripes = available_parallelism().map_or(1, |n| n.get());
+        Self::with_stripes(stripes)
+    }
+}
+
+impl Counters {
+    pub fn with_stripes(stripes: usize) -> Self {
+        Self {
+            stripes: (0..stripes.max(1)).map(|_| Stripe::new()).collect(),
+            remote: Stripe::new(),
+            nodes: Mutex::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::sum(&sweep(self.stripes.iter().chain([&self.remote])))
+    }
+
+    /// The full CRDT state: this node's votes under `node` along with every
+    /// other node's entry known here.
+    pub fn state(&self, node: &str) -> PnCounter {
+        let mut local = [0u64; STRIPE_SLOTS];
+        for counts in sweep(self.stripes.iter()) {
+            for (total, count) in local.iter_mut().zip(counts) {
+                *total += count as u64;
+            }
+        }
+
+        let mut state = self.nodes.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        state.set(node, &local[..RETRACTED], &local[RETRACTED..]);
+        state
+    }
+
+    /// Merges another node's state. Entries for `node` are ignored; this
+    /// node's own stripes are the truth about its votes. Returns whether the
+    /// counts changed.
+    pub fn merge(&self, node: &str, other: &PnCounter) -> bool {
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        if !nodes.merge(&other.without(node)) {
+            return false;
+        }
+        self.sum_remote(&nodes);
+        true
+    }
+
+    fn sum_remote(&self, nodes: &PnCounter) {
+        let mut remote = nodes.added.value(SLOTS);
+        remote.extend(nodes.retracted.value(SLOTS));
+        self.remote.write(|counts| {
+            for (counter, value) in counts.iter().zip(remote) {
+                counter.store(value as usize, Relaxed);
+            }
+        });
+        self.remote.flag(ALL_CHANGED);
+    }
+
+    /// Records one ballot adding `amount` to each option index in a single
+    /// write, so readers never see part of a multi-option or scored ballot.
+    pub fn record(&self, tallies: &[(usize, usize)]) {
+        self.replace(None, Some(tallies));
+    }
+
+    /// Takes back a ballot previously passed to `record`.
+    pub fn retract(&self, tallies: &[(usize, usize)]) {
+        self.replace(Some(tallies), None);
+    }
+
+    /// Swaps one recorded ballot for another in a single write, so the
+    /// change is observed as one delta.
+    pub fn replace(&self, old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>) {
+        let stripe = self.local();
+        let mut changed = 0;
+        stripe.write(|counts| {
+            if let Some(tallies) = old {
+                for &(index, amount) in tallies {
+                    counts[RETRACTED + index].fetch_add(amount, Relaxed);
+                    changed |= 1 << index;
+                }
+                counts[RETRACTED + BALLOTS].fetch_add(1, Relaxed);
+            }
+            if let Some(tallies) = new {
+                for &(index, amount) in tallies {
+                    counts[index].fetch_add(amount, Relaxed);
+                    changed |= 1 << index;
+                }
+                counts[BALLOTS].fetch_add(1, Relaxed);
+            }
+        });
+        stripe.flag(changed);
+    }
+
+    /// The options written since the last call, in display order. Writes
+    /// landing meanwhile are kept for the next call, so a snapshot taken
+    /// right after this one includes everything it reported.
+    pub fn take_changed(&self) -> Vec<&'static str> {
+        let changed = self
+            .stripes
+            .iter()
+            .chain([&self.remote])
+            .fold(0, |changed, stripe| {
+                // Skipping stripes with nothing new keeps their lines shared.
+                if stripe.changed.load(Relaxed) == 0 {
+                    changed
+                } else {
+                    changed | stripe.changed.swap(0, Acquire)
+                }
+            });
+        COLORS
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| changed & 1 << index != 0)
+            .map(|(_, color)| color)
+            .collect()
+    }
+
+    /// Starts over from a saved state. `node` names this run of the process
+    /// only, so every saved entry belongs to some other node.
+    pub fn restore(&self, node: &str, saved: &PnCounter) {
+        for stripe in self.stripes.iter() {
+            stripe.write(|counts| {
+                for counter in counts {
+                    counter.store(0, Relaxed);
+                }
+            });
+        }
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        *nodes = saved.without(node);
+        self.sum_remote(&nodes);
+    }
+
+    fn local(&self) -> &Stripe {
+        let index = STRIPE.with(|stripe| *stripe);
+        &self.stripes[index % self.stripes.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Every ballot spreads the same number of units over the options, so
+    // whatever ballots a snapshot counts, its total is that many times its
+    // ballots.
+    const UNITS: usize = 3;
+
+    fn ballot() -> impl Strategy<Value = Vec<(usize, usize)>> {
+        prop::collection::vec(0..COLORS.len(), UNITS)
+            .prop_map(|options| options.into_iter().map(|option| (option, 1)).collect())
+    }
+
+    fn tally<'a>(ballots: impl IntoIterator<Item = &'a Vec<(usize, usize)>>) -> Snapshot {
+        let mut tally = Snapshot::default();
+        for ballot in ballots {
+            for &(index, amount) in ballot {
+                match index {
+                    0 => tally.red += amount,
+                    1 => tally.green += amount,
+                    2 => tally.blue += amount,
+                    _ => tally.purple += amount,
+                }
+                tally.total += amount;
+            }
+            tally.ballots += 1;
+        }
+        tally
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        // Each voter thread records ballots and hands every other one to a
+        // thread of its own to retract, so retractions land on another
+        // stripe than the votes they undo. Meanwhile no snapshot may count
+        // part of a ballot, a retraction without its vote, or more than was
+        // ever cast.
+        #[test]
+        fn snapshots_only_count_whole_ballots(
+            stripes in 2usize..6,
+            voters in prop::collection::vec(prop::collection::vec(ballot(), 1..40), 1..4),
+        ) {
+            let cast = tally(voters.iter().flatten());
+            let counters = Counters::with_stripes(stripes);
+            let done = std::sync::atomic::AtomicBool::new(false);
+
+            let snapshots = std::thread::scope(|scope| {
+                let reader = scope.spawn(|| {
+                    let mut snapshots = 0;
+                    while !done.load(Acquire) {
+                        let snapshot = counters.snapshot();
+                        assert_eq!(snapshot.total, UNITS * snapshot.ballots, "{snapshot:?}");
+                        assert!(snapshot.ballots <= cast.ballots, "{snapshot:?}");
+                        for color in COLORS {
+                            assert!(snapshot.get(color) <= cast.get(color), "{snapshot:?}");
+                        }
+                        snapshots += 1;
+                    }
+                    snapshots
+                });
+
+                let mut handles = Vec::new();
+                for ballots in &voters {
+                    let counters = &counters;
+                    let (tx, rx) = std::sync::mpsc::channel();
+                    handles.push(scope.spawn(move || {
+                        for (i, ballot) in ballots.iter().enumerate() {
+                            counters.record(ballot);
+                            if i % 2 == 1 {
+                                tx.send(ballot).unwrap();
+                            }
+                        }
+                    }));
+                    handles.push(scope.spawn(move || {
+                        for ballot in rx {
+                            counters.retract(ballot);
+                        }
+                    }));
+                }
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+                done.store(true, Release);
+                reader.join().unwrap()
+            });
+            prop_assert!(snapshots > 0);
+
+            // Only the ballots nobody retracted are left standing.
+            let kept = tally(voters.iter().flat_map(|ballots| ballots.iter().step_by(2)));
+            prop_assert_eq!(counters.snapshot(), kept);
+        }
+    }
+}
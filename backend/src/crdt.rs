@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A grow-only counter vector with one entry per node. Every node only ever
+/// raises its own entry, so merging keeps the larger count of each node and
+/// slot: applying the same state twice, late or out of order changes nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter {
+    nodes: BTreeMap<String, Vec<u64>>,
+}
+
+impl GCounter {
+    /// Raises `node`'s entry to `counts`, slot by slot. Nodes that never
+    /// counted anything get no entry.
+    pub fn set(&mut self, node: &str, counts: &[u64]) -> bool {
+        match self.nodes.get_mut(node) {
+            Some(entry) => raise(entry, counts),
+            None if counts.iter().all(|&count| count == 0) => false,
+            None => {
+                self.nodes.insert(node.to_string(), counts.to_vec());
+                true
+            }
+        }
+    }
+
+    /// Folds in another replica's view. Returns whether anything grew.
+    pub fn merge(&mut self, other: &GCounter) -> bool {
+        other
+            .nodes
+            .iter()
+            .fold(false, |grew, (node, counts)| self.set(node, counts) || grew)
+    }
+
+    /// The sum over every node, `slots` wide.
+    pub fn value(&self, slots: usize) -> Vec<u64> {
+        let mut value = vec![0; slots];
+        for counts in self.nodes.values() {
+            for (total, count) in value.iter_mut().zip(counts) {
+                *total += count;
+            }
+        }
+        value
+    }
+
+    /// Every node's entry.
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, &[u64])> {
+        self.nodes
+            .iter()
+            .map(|(node, counts)| (node.as_str(), counts.as_slice()))
+    }
+
+    /// `node`'s entry; empty if it never counted anything.
+    pub fn get(&self, node: &str) -> &[u64] {
+        self.nodes.get(node).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// A copy leaving out `skip`'s entry.
+    pub fn without(&self, skip: &str) -> Self {
+        let mut counter = self.clone();
+        counter.nodes.remove(skip);
+        counter
+    }
+}
+
+fn raise(entry: &mut Vec<u64>, counts: &[u64]) -> bool {
+    if entry.len() < counts.len() {
+        entry.resize(counts.len(), 0);
+    }
+    let mut grew = false;
+    for (current, &count) in entry.iter_mut().zip(counts) {
+        if count > *current {
+            *current = count;
+            grew = true;
+        }
+    }
+    grew
+}
+
+/// A counter vector that can also go down, as a pair of G-counters: what
+/// every node added and what it took back.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    pub added: GCounter,
+    pub retracted: GCounter,
+}
+
+impl PnCounter {
+    pub fn set(&mut self, node: &str, added: &[u64], retracted: &[u64]) -> bool {
+        let grew = self.added.set(node, added);
+        self.retracted.set(node, retracted) || grew
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) -> bool {
+        let grew = self.added.merge(&other.added);
+        self.retracted.merge(&other.retracted) || grew
+    }
+
+    pub fn without(&self, skip: &str) -> Self {
+        Self {
+            added: self.added.without(skip),
+            retracted: self.retracted.without(skip),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.retracted.is_empty()
+    }
+}
+
+/// When and on which replica a write happened, in Unix milliseconds. The
+/// later stamp wins; the replica name settles writes made in the same
+/// millisecond.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Stamp {
+    pub at: u64,
+    pub node: String,
+}
+
+impl Stamp {
+    /// A stamp for a write on `node` that overrides `previous`, even if this
+    /// replica's clock is behind the one that wrote it.
+    pub fn after(previous: Option<&Stamp>, node: &str) -> Self {
+        // Benches include this file on its own, without `poll`.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        Self {
+            at: now.max(previous.map_or(0, |previous| previous.at + 1)),
+            node: node.to_string(),
+        }
+    }
+}
+
+/// A value replicas overwrite, settled by the latest stamp.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Register<T> {
+    pub value: T,
+    pub stamp: Stamp,
+}
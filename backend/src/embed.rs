@@ -0,0 +1,169 @@
+use crate::{
+    chart::{escape, Chart, HEIGHT, WIDTH},
+    error::AppError,
+    polls::find,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::debug;
+
+// The widget reloads itself this often, in seconds.
+const REFRESH: u32 = 10;
+
+// How long chat tools and browsers may keep a chart image, in seconds.
+const IMAGE_MAX_AGE: u32 = 10;
+
+// How long consumers may keep an oEmbed response, in seconds.
+const OEMBED_CACHE_AGE: u32 = 300;
+
+const PROVIDER_NAME: &str = "PickOne";
+
+fn embed_url(state: &AppState, index: usize) -> String {
+    format!("{}/embed/polls/{index}", state.config.public_url)
+}
+
+fn chart(state: &AppState, index: usize) -> Result<Chart, AppError> {
+    Ok(Chart::new(index, find(state, index)?))
+}
+
+/// A poll's results as a small page for iframes, without the Svelte app.
+pub async fn embed_handler(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Embed of poll {} requested", index);
+    let chart = chart(&state, index)?;
+    let url = embed_url(&state, index);
+    let oembed = format!(
+        "{}/oembed?format=json&url={}",
+        state.config.public_url,
+        form_urlencoded::byte_serialize(url.as_bytes()).collect::<String>()
+    );
+    let title = escape(&chart.title);
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta http-equiv="refresh" content="{REFRESH}">
+<title>{title}</title>
+<link rel="alternate" type="application/json+oembed" href="{oembed}" title="{title}">
+<meta property="og:title" content="{title}">
+<meta property="og:image" content="{url}/chart.png">
+<style>html,body{{margin:0;background:#faf4ee}}svg{{display:block;width:100%;height:auto}}</style>
+</head>
+<body>
+{svg}
+</body>
+</html>
+"#,
+        oembed = escape(&oembed),
+        url = escape(&url),
+        svg = chart.svg(),
+    );
+    Ok(([(CACHE_CONTROL, "no-cache")], Html(page)))
+}
+
+pub async fn svg_handler(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError> {
+    let svg = chart(&state, index)?.svg();
+    Ok((
+        [
+            (CONTENT_TYPE, "image/svg+xml".to_string()),
+            (CACHE_CONTROL, format!("public, max-age={IMAGE_MAX_AGE}")),
+        ],
+        svg,
+    ))
+}
+
+/// The chart as a PNG, for chat tools that don't render SVG when unfurling.
+pub async fn png_handler(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<impl IntoResponse, AppError> {
+    let png = chart(&state, index)?.png()?;
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png".to_string()),
+            (CACHE_CONTROL, format!("public, max-age={IMAGE_MAX_AGE}")),
+        ],
+        png,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct OEmbedQuery {
+    url: String,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<String>,
+}
+
+/// Describes an embed link for wikis and chat tools, per oEmbed 1.0. Only
+/// the JSON format is offered.
+pub async fn oembed_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<OEmbedQuery>,
+) -> Result<Json<Value>, AppError> {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return Err(AppError::NotImplemented("Only json is offered".into()));
+    }
+    // Links may come through another host name or scheme, so only the path
+    // is looked at.
+    let index = query
+        .url
+        .split_once("/embed/polls/")
+        .and_then(|(_, rest)| {
+            rest.split(['/', '?', '#'])
+                .next()
+                .and_then(|index| index.parse().ok())
+        })
+        .ok_or_else(|| AppError::NotFound(format!("No embed at {}", query.url)))?;
+    let chart = chart(&state, index)?;
+    debug!("oEmbed of poll {} requested", index);
+
+    // Shrunk to fit, keeping the chart's proportions.
+    let scale = [
+        query.maxwidth.map(|max| max as f64 / WIDTH as f64),
+        query.maxheight.map(|max| max as f64 / HEIGHT as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+    let width = (WIDTH as f64 * scale).floor() as u32;
+    let height = (HEIGHT as f64 * scale).floor() as u32;
+
+    let url = embed_url(&state, index);
+    Ok(Json(json!({
+        "version": "1.0",
+        "type": "rich",
+        "provider_name": PROVIDER_NAME,
+        "provider_url": state.config.public_url,
+        "title": chart.title,
+        "html": format!(
+            r#"<iframe src="{url}" width="{width}" height="{height}" title="{}" style="border:0" loading="lazy"></iframe>"#,
+            escape(&chart.title)
+        ),
+        "width": width,
+        "height": height,
+        "thumbnail_url": format!("{url}/chart.png"),
+        "thumbnail_width": WIDTH,
+        "thumbnail_height": HEIGHT,
+        "cache_age": OEMBED_CACHE_AGE,
+    })))
+}
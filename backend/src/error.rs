@@ -1,15 +1,17 @@
 use axum::{
+    extract::ws::Message,
     http::{header::InvalidHeaderValue, StatusCode},
     response::{IntoResponse, Response},
-    Error as AxumError,
 };
+use png::EncodingError;
 use prometheus::Error as prometheusError;
+use redis::RedisError;
 use serde_json::Error as jsonError;
 use std::{env::VarError, io::Error as IOError, string::FromUtf8Error};
 use tempfile::PersistError;
 use thiserror::Error;
-use tokio::sync::broadcast::error::SendError;
-use tracing::{dispatcher::SetGlobalDefaultError, error};
+use tokio::sync::{broadcast::error::SendError, mpsc::error::TrySendError};
+use tracing::{dispatcher::SetGlobalDefaultError, error, warn};
 use tracing_subscriber::filter::ParseError;
 
 #[derive(Error, Debug)]
@@ -45,20 +47,63 @@ pub enum AppError {
     Persist(#[from] PersistError),
 
     #[error("Broadcast error: {0}")]
-    Broadcast(#[from] SendError<String>),
+    Broadcast(#[from] SendError<Message>),
 
-    #[error("Websocket send error: {0}")]
-    WebSocketSend(#[from] AxumError),
+    #[error("Outbound queue error: {0}")]
+    OutboundQueue(#[from] TrySendError<Message>),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] RedisError),
+
+    #[error("PNG encoding error: {0}")]
+    Png(#[from] EncodingError),
+
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Not implemented: {0}")]
+    NotImplemented(String),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, message) = {
-            error!("Server error: {}", self);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".to_string(),
-            )
+        let (status, message) = match self {
+            AppError::Unauthorized => {
+                warn!("Rejected admin request without a valid token");
+                (StatusCode::UNAUTHORIZED, "Unauthorized".to_string())
+            }
+            AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Conflict(message) => (StatusCode::CONFLICT, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AppError::NotImplemented(message) => (StatusCode::NOT_IMPLEMENTED, message),
+            AppError::TooManyRequests(message) => (StatusCode::TOO_MANY_REQUESTS, message),
+            AppError::ServiceUnavailable(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+            _ => {
+                error!("Server error: {}", self);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error".to_string(),
+                )
+            }
         };
 
         (status, message).into_response()
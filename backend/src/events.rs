@@ -0,0 +1,173 @@
+use crate::{state::AppState, websocket::poll_payload};
+use axum::{
+    extract::{ws::Message, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{atomic::Ordering::Relaxed, Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+// Frames kept for viewers resuming with `Last-Event-ID`; anyone further
+// behind starts over from a snapshot.
+const REPLAY_EVENTS: usize = 256;
+
+// Comment lines sent while nothing happens, so proxies keep the stream open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The audience frames, numbered for Server-Sent Events.
+pub struct Events {
+    tx: broadcast::Sender<(u64, Arc<str>)>,
+    recent: Mutex<Recent>,
+}
+
+#[derive(Default)]
+struct Recent {
+    last: u64,
+    frames: VecDeque<(u64, Arc<str>)>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(100).0,
+            recent: Mutex::default(),
+        }
+    }
+}
+
+impl Events {
+    fn push(&self, frame: Arc<str>) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.last += 1;
+        let id = recent.last;
+        if recent.frames.len() == REPLAY_EVENTS {
+            recent.frames.pop_front();
+        }
+        recent.frames.push_back((id, Arc::clone(&frame)));
+        // Sent under the lock, so subscribers see ids in order.
+        let _ = self.tx.send((id, frame));
+    }
+
+    // Frames lost to a lagging relay can't be replayed.
+    fn forget(&self) {
+        self.recent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .frames
+            .clear();
+    }
+
+    /// The frames after `id`, if all of them are still kept.
+    fn since(&self, id: u64) -> Option<Vec<(u64, Arc<str>)>> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if id > recent.last {
+            return None;
+        }
+        let first = recent.frames.front().map_or(recent.last + 1, |(id, _)| *id);
+        (first <= id + 1).then(|| {
+            recent
+                .frames
+                .iter()
+                .filter(|(frame_id, _)| *frame_id > id)
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn last(&self) -> u64 {
+        self.recent.lock().unwrap_or_else(|e| e.into_inner()).last
+    }
+}
+
+/// Numbers everything the audience is sent over websockets.
+pub fn start(state: &Arc<AppState>) {
+    let mut rx = state.broadcast_tx.subscribe();
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(Message::Text(text)) => state.events.push(text.as_str().into()),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event stream skipped {} frames", skipped);
+                    state.events.forget();
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+// Ids carry the replica's node name, so resuming against another replica
+// starts from a snapshot instead of replaying the wrong frames.
+fn event_id(state: &AppState, id: u64) -> String {
+    format!("{}:{}", state.cluster.node, id)
+}
+
+fn last_event_id(state: &AppState, headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get("last-event-id")?.to_str().ok()?;
+    let (node, id) = value.rsplit_once(':')?;
+    (node == state.cluster.node).then(|| id.parse().ok())?
+}
+
+/// Streams the audience's updates to viewers that can't use websockets,
+/// starting with the same state a websocket gets on connecting.
+pub async fn events_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Subscribed first, so nothing falls between the start and the stream.
+    let rx = state.events.tx.subscribe();
+
+    let resumed =
+        last_event_id(&state, &headers).and_then(|id| Some((id, state.events.since(id)?)));
+    let (after, first) = match resumed {
+        Some((id, missed)) => {
+            debug!("SSE viewer resumed after event {}", id);
+            let after = missed.last().map_or(id, |(id, _)| *id);
+            let events = missed
+                .into_iter()
+                .map(|(id, frame)| Event::default().id(event_id(&state, id)).data(&*frame))
+                .collect();
+            (after, events)
+        }
+        None => {
+            debug!("New SSE viewer");
+            let after = state.events.last();
+            let mut initial = poll_payload(&state, false);
+            initial["type"] = json!("initial");
+            initial["count"] = json!(state.total_users.load(Relaxed));
+            initial["presenter"] = json!(false);
+            let event = Event::default()
+                .id(event_id(&state, after))
+                .data(initial.to_string());
+            (after, vec![event])
+        }
+    };
+
+    let live = stream::unfold((rx, state), move |(mut rx, state)| async move {
+        loop {
+            match rx.recv().await {
+                Ok((id, _)) if id <= after => continue,
+                Ok((id, frame)) => {
+                    let event = Event::default().id(event_id(&state, id)).data(&*frame);
+                    return Some((event, (rx, state)));
+                }
+                // Ending the stream makes the browser reconnect with the
+                // last id it saw, and replay or start over from there.
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream::iter(first).chain(live).map(Ok))
+        .keep_alive(KeepAlive::new().interval(KEEP_ALIVE).text("keep-alive"))
+}
@@ -0,0 +1,469 @@
+use crate::{
+    admin::{is_admin_token, Admin},
+    counters::COLORS,
+    poll::{BallotMode, Poll, PollStatus, Privacy, Visibility},
+    polls::{cast, create, find, shown, watch},
+    session::SessionEntry,
+    state::AppState,
+    throttle::ClientIp,
+    websocket::{control, Command},
+};
+use async_graphql::{
+    http::{
+        WebSocket as GraphQLWebSocket, WebSocketProtocols as Protocols, WsMessage,
+        ALL_WEBSOCKET_PROTOCOLS,
+    },
+    Context, Data, InputObject, Json as GraphQLJson, Object, Request, Response, Result, Schema,
+    SimpleObject, Subscription,
+};
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap},
+    response::IntoResponse,
+    Extension, Json,
+};
+use futures_util::{future::ready, SinkExt, Stream, StreamExt};
+use serde_json::Value;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{atomic::Ordering::Acquire, Arc},
+};
+use tracing::debug;
+
+// Deeper queries than the schema can need are refused.
+const MAX_DEPTH: usize = 8;
+
+pub type VotingSchema = Schema<Query, Mutation, SubscriptionRoot>;
+
+pub fn schema(state: &Arc<AppState>) -> VotingSchema {
+    Schema::build(Query, Mutation, SubscriptionRoot)
+        .data(Arc::clone(state))
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+// Whether the request carried the admin token.
+struct Privileged(bool);
+
+fn state<'a>(ctx: &Context<'a>) -> &'a Arc<AppState> {
+    ctx.data_unchecked()
+}
+
+// Every request and subscriber connection records where it came from.
+fn address(ctx: &Context<'_>) -> IpAddr {
+    ctx.data_opt::<ClientIp>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |client| client.0)
+}
+
+fn privileged(ctx: &Context<'_>) -> bool {
+    ctx.data_opt::<Privileged>()
+        .is_some_and(|privileged| privileged.0)
+}
+
+fn require_admin(ctx: &Context<'_>) -> Result<()> {
+    if privileged(ctx) {
+        Ok(())
+    } else {
+        Err("Unauthorized".into())
+    }
+}
+
+/// One option's count, with its average score on score polls.
+#[derive(SimpleObject, Clone, PartialEq)]
+struct OptionCount {
+    option: String,
+    count: usize,
+    average: Option<f64>,
+}
+
+/// A poll's counts. Without the admin token only `ballots` is filled in
+/// while `hidden` is set.
+#[derive(SimpleObject, Clone, PartialEq)]
+struct Tally {
+    poll: usize,
+    current: bool,
+    status: PollStatus,
+    locked: bool,
+    hidden: bool,
+    total: usize,
+    ballots: usize,
+    options: Vec<OptionCount>,
+}
+
+fn tally(state: &AppState, index: usize, poll: &Poll, privileged: bool) -> Tally {
+    let shown = shown(poll, privileged);
+    let averages = (poll.ballot == BallotMode::Score).then(|| shown.averages());
+    Tally {
+        poll: index,
+        current: index == state.session.index(),
+        status: poll.status(),
+        locked: poll.locked.is_on(),
+        hidden: !poll.results_visible(),
+        total: shown.total,
+        ballots: shown.ballots,
+        options: COLORS
+            .iter()
+            .map(|color| OptionCount {
+                option: color.to_string(),
+                count: shown.get(color).unwrap_or_default(),
+                average: averages
+                    .as_ref()
+                    .and_then(|averages| averages.get(color).copied()),
+            })
+            .collect(),
+    }
+}
+
+/// A ballot of an attributed poll, as kept in its ledger.
+#[derive(SimpleObject)]
+struct Ballot {
+    voter: Option<String>,
+    name: Option<String>,
+    cast_at: Option<u64>,
+    choices: Vec<OptionCount>,
+    ranking: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+struct Presence {
+    // Open websocket connections on this replica.
+    connected: usize,
+    total: usize,
+}
+
+struct PollNode(usize);
+
+impl PollNode {
+    fn poll<'a>(&self, ctx: &Context<'a>) -> &'a Poll {
+        state(ctx)
+            .session
+            .polls()
+            .get(self.0)
+            .expect("Polls stay in the session")
+    }
+}
+
+#[Object(name = "Poll")]
+impl PollNode {
+    async fn index(&self) -> usize {
+        self.0
+    }
+
+    async fn current(&self, ctx: &Context<'_>) -> bool {
+        self.0 == state(ctx).session.index()
+    }
+
+    async fn title(&self, ctx: &Context<'_>) -> Option<String> {
+        self.poll(ctx).title.clone()
+    }
+
+    async fn ballot(&self, ctx: &Context<'_>) -> BallotMode {
+        self.poll(ctx).ballot
+    }
+
+    async fn results(&self, ctx: &Context<'_>) -> Visibility {
+        self.poll(ctx).results
+    }
+
+    async fn privacy(&self, ctx: &Context<'_>) -> Privacy {
+        self.poll(ctx).privacy
+    }
+
+    async fn status(&self, ctx: &Context<'_>) -> PollStatus {
+        self.poll(ctx).status()
+    }
+
+    async fn options(&self) -> Vec<&str> {
+        COLORS.to_vec()
+    }
+
+    async fn tally(&self, ctx: &Context<'_>) -> Tally {
+        tally(state(ctx), self.0, self.poll(ctx), privileged(ctx))
+    }
+
+    /// Every ballot cast on an attributed poll. Needs the admin token.
+    async fn history(&self, ctx: &Context<'_>) -> Result<Vec<Ballot>> {
+        require_admin(ctx)?;
+        let poll = self.poll(ctx);
+        if poll.privacy != Privacy::Attributed {
+            return Err(format!("Poll {} is anonymous", self.0).into());
+        }
+        Ok(poll
+            .ballots
+            .saved()
+            .into_iter()
+            .map(|ballot| Ballot {
+                voter: ballot.voter,
+                name: ballot.name,
+                cast_at: ballot.cast_at,
+                choices: ballot
+                    .tallies
+                    .into_iter()
+                    .map(|(option, count)| OptionCount {
+                        option,
+                        count,
+                        average: None,
+                    })
+                    .collect(),
+                ranking: ballot.ranking,
+            })
+            .collect())
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn polls(&self, ctx: &Context<'_>) -> Vec<PollNode> {
+        (0..state(ctx).session.polls().len())
+            .map(PollNode)
+            .collect()
+    }
+
+    async fn poll(&self, ctx: &Context<'_>, index: usize) -> Option<PollNode> {
+        state(ctx)
+            .session
+            .polls()
+            .get(index)
+            .map(|_| PollNode(index))
+    }
+
+    /// The poll the audience is currently following.
+    async fn current(&self, ctx: &Context<'_>) -> PollNode {
+        PollNode(state(ctx).session.index())
+    }
+
+    async fn presence(&self, ctx: &Context<'_>) -> Presence {
+        let state = state(ctx);
+        Presence {
+            connected: state.concurrent_users.load(Acquire),
+            total: state.total_users.load(Acquire),
+        }
+    }
+}
+
+/// Settings for a new poll. Anything left out falls back to the poll
+/// settings from the environment, as in an entry of the session file.
+#[derive(InputObject)]
+struct CreatePollInput {
+    title: Option<String>,
+    ballot: Option<BallotMode>,
+    results: Option<Visibility>,
+    privacy: Option<Privacy>,
+    max_choices: Option<usize>,
+    max_score: Option<usize>,
+    max_text_bytes: Option<usize>,
+    correct: Option<String>,
+    /// How long a quiz question runs, up to an hour.
+    seconds: Option<u64>,
+    /// Unix seconds.
+    opens_at: Option<u64>,
+    closes_at: Option<u64>,
+}
+
+#[derive(SimpleObject)]
+struct Vote {
+    voter: String,
+    // The replies a websocket would have been sent.
+    replies: Vec<GraphQLJson<Value>>,
+    tally: Tally,
+}
+
+// Presenter commands answer with the poll the session is on afterwards.
+fn command(ctx: &Context<'_>, command: Command) -> Result<PollNode> {
+    require_admin(ctx)?;
+    let state = state(ctx);
+    control(state, command)?;
+    Ok(PollNode(state.session.index()))
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Votes on the current poll. `message` takes the same form as a
+    /// websocket frame. `voter` is required unless presenting: an id the
+    /// client keeps, reused to change or retract a ballot.
+    async fn vote(
+        &self,
+        ctx: &Context<'_>,
+        poll: usize,
+        message: String,
+        voter: Option<String>,
+        name: Option<String>,
+    ) -> Result<Vote> {
+        let state = state(ctx);
+        let privileged = privileged(ctx);
+        let (voter, replies) =
+            cast(state, poll, &message, voter, name, privileged, address(ctx)).await?;
+        Ok(Vote {
+            voter: voter.to_string(),
+            replies: replies.into_iter().map(GraphQLJson).collect(),
+            tally: tally(state, poll, find(state, poll)?, privileged),
+        })
+    }
+
+    /// Adds a poll at the end of the session.
+    async fn create_poll(&self, ctx: &Context<'_>, input: CreatePollInput) -> Result<PollNode> {
+        require_admin(ctx)?;
+        let entry = SessionEntry {
+            title: input.title,
+            ballot: input.ballot,
+            results: input.results,
+            privacy: input.privacy,
+            max_choices: input.max_choices,
+            max_score: input.max_score,
+            max_text_bytes: input.max_text_bytes,
+            correct: input.correct,
+            seconds: input.seconds,
+            opens_at: input.opens_at,
+            closes_at: input.closes_at,
+        };
+        Ok(PollNode(create(state(ctx), entry)?))
+    }
+
+    /// Moves everyone to poll `to`, or to the next one.
+    async fn advance(&self, ctx: &Context<'_>, to: Option<usize>) -> Result<PollNode> {
+        command(ctx, Command::Advance { to })
+    }
+
+    async fn reveal(&self, ctx: &Context<'_>) -> Result<PollNode> {
+        command(ctx, Command::Reveal)
+    }
+
+    async fn hide(&self, ctx: &Context<'_>) -> Result<PollNode> {
+        command(ctx, Command::Hide)
+    }
+
+    async fn lock(&self, ctx: &Context<'_>) -> Result<PollNode> {
+        command(ctx, Command::Lock)
+    }
+
+    async fn unlock(&self, ctx: &Context<'_>) -> Result<PollNode> {
+        command(ctx, Command::Unlock)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription(name = "Subscription")]
+impl SubscriptionRoot {
+    /// The tally of poll `poll`, or of whichever poll is current, now and
+    /// again whenever it changes.
+    async fn tally(
+        &self,
+        ctx: &Context<'_>,
+        poll: Option<usize>,
+    ) -> Result<impl Stream<Item = Tally>> {
+        let state = state(ctx);
+        if let Some(index) = poll {
+            find(state, index)?;
+        }
+        let privileged = privileged(ctx);
+        Ok(watch(state, poll, privileged, move |state, index, poll| {
+            tally(state, index, poll, privileged)
+        }))
+    }
+}
+
+/// Runs a GraphQL query or mutation.
+pub async fn graphql_handler(
+    admin: Option<Admin>,
+    client: ClientIp,
+    Extension(schema): Extension<VotingSchema>,
+    Json(request): Json<Request>,
+) -> Json<Response> {
+    Json(
+        schema
+            .execute(request.data(Privileged(admin.is_some())).data(client))
+            .await,
+    )
+}
+
+/// An in-browser editor for trying queries against the API. Only debug
+/// builds serve it, so production exposes nothing beyond the API itself.
+#[cfg(debug_assertions)]
+pub async fn playground_handler() -> axum::response::Html<String> {
+    use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+    use axum::response::Html;
+
+    Html(playground_source(
+        GraphQLPlaygroundConfig::new("/api/graphql").subscription_endpoint("/api/graphql/ws"),
+    ))
+}
+
+/// Serves subscriptions over the `graphql-transport-ws` and `graphql-ws`
+/// protocols. Browsers cannot set headers on websockets, so presenters send
+/// the admin token as `token` in the connection init payload.
+pub async fn graphql_ws_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    client: ClientIp,
+    State(state): State<Arc<AppState>>,
+    Extension(schema): Extension<VotingSchema>,
+) -> impl IntoResponse {
+    let protocol = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|protocols| {
+            protocols
+                .split(',')
+                .find_map(|protocol| protocol.trim().parse::<Protocols>().ok())
+        })
+        .unwrap_or(Protocols::SubscriptionsTransportWS);
+
+    ws.protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| serve(socket, schema, protocol, client, state))
+}
+
+async fn serve(
+    socket: WebSocket,
+    schema: VotingSchema,
+    protocol: Protocols,
+    client: ClientIp,
+    state: Arc<AppState>,
+) {
+    debug!("GraphQL subscriber connected");
+    let (mut sink, stream) = socket.split();
+    let input = stream
+        .take_while(|message| ready(message.is_ok()))
+        .filter_map(|message| {
+            ready(match message {
+                Ok(Message::Text(text)) => Some(text.as_str().as_bytes().to_vec()),
+                Ok(Message::Binary(bytes)) => Some(bytes.to_vec()),
+                _ => None,
+            })
+        });
+
+    let mut outgoing = GraphQLWebSocket::new(schema, input, protocol).on_connection_init(
+        move |payload| async move {
+            let privileged = match payload.get("token").and_then(Value::as_str) {
+                None => false,
+                Some(token) if is_admin_token(&state, token) => true,
+                Some(_) => return Err("Unauthorized".into()),
+            };
+            let mut data = Data::default();
+            data.insert(Privileged(privileged));
+            data.insert(client);
+            Ok(data)
+        },
+    );
+
+    while let Some(message) = outgoing.next().await {
+        let message = match message {
+            WsMessage::Text(text) => Message::Text(text.into()),
+            WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })),
+        };
+        if sink.send(message).await.is_err() {
+            break;
+        }
+    }
+    debug!("GraphQL subscriber disconnected");
+}
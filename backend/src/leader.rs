@@ -0,0 +1,238 @@
+use crate::{
+    cluster::{Cluster, Gossip},
+    error::AppError,
+    poll::unix_now_millis,
+    state::AppState,
+};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{info, warn};
+
+// How long a claim holds. A leader that stops renewing, e.g. because it
+// died, is replaced after at most this long.
+const LEASE_TTL: Duration = Duration::from_secs(10);
+
+const RENEW_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElectionKind {
+    // The only replica always leads.
+    Single,
+    // A lease file on a volume every replica mounts.
+    File,
+    // Heartbeats over the cluster transport.
+    Peer,
+}
+
+impl FromStr for ElectionKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "single" => Ok(Self::Single),
+            "file" => Ok(Self::File),
+            "peer" => Ok(Self::Peer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decides which replica runs the singleton tasks.
+pub trait Election: Send + Sync {
+    /// Claims or renews the lease for this replica. Returns whether it holds
+    /// the lease for the next `LEASE_TTL`.
+    fn campaign<'a>(&'a self, cluster: &'a Cluster) -> BoxFuture<'a, bool>;
+
+    /// Notes that `node` is alive.
+    fn observe(&self, _node: &str) {}
+}
+
+pub struct Single;
+
+impl Election for Single {
+    fn campaign<'a>(&'a self, _: &'a Cluster) -> BoxFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseRecord {
+    holder: String,
+    expires_at: u64,
+}
+
+/// The holder and expiry of the lease kept in a file, rewritten under an
+/// exclusive lock. Needs a filesystem whose locks every replica sees.
+pub struct FileLease {
+    path: PathBuf,
+}
+
+impl FileLease {
+    pub fn new(path: &str) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Election for FileLease {
+    fn campaign<'a>(&'a self, cluster: &'a Cluster) -> BoxFuture<'a, bool> {
+        let path = self.path.clone();
+        let node = cluster.node.clone();
+        Box::pin(async move {
+            match spawn_blocking(move || claim(&path, &node)).await {
+                Ok(Ok(held)) => held,
+                Ok(Err(e)) => {
+                    warn!("Failed to claim the leader lease: {}", e);
+                    false
+                }
+                Err(e) => {
+                    warn!("Leader lease task failed: {}", e);
+                    false
+                }
+            }
+        })
+    }
+}
+
+fn claim(path: &Path, node: &str) -> Result<bool, AppError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Released when the file is dropped.
+    File::lock(&file)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let now = unix_now_millis();
+    if let Ok(lease) = serde_json::from_str::<LeaseRecord>(&contents) {
+        if lease.holder != node && lease.expires_at > now {
+            return Ok(false);
+        }
+    }
+
+    let lease = LeaseRecord {
+        holder: node.to_string(),
+        expires_at: now + LEASE_TTL.as_millis() as u64,
+    };
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(serde_json::to_string(&lease)?.as_bytes())?;
+    file.sync_data()?;
+    Ok(true)
+}
+
+/// Every replica heartbeats over the cluster transport; the live replica
+/// with the lowest node name leads. A new replica listens for one lease
+/// before claiming, so it does not lead over peers it has not heard yet.
+pub struct Peer {
+    started: Instant,
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            seen: Mutex::default(),
+        }
+    }
+}
+
+impl Election for Peer {
+    fn campaign<'a>(&'a self, cluster: &'a Cluster) -> BoxFuture<'a, bool> {
+        cluster.publish(Gossip::Heartbeat);
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, at| at.elapsed() < LEASE_TTL);
+        let held =
+            self.started.elapsed() >= LEASE_TTL && seen.keys().all(|node| *node > cluster.node);
+        Box::pin(async move { held })
+    }
+
+    fn observe(&self, node: &str) {
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(node.to_string(), Instant::now());
+    }
+}
+
+/// Whether this replica currently leads.
+pub struct Leader {
+    election: Box<dyn Election>,
+    // Unix millis until which the last successful claim holds. Checked on
+    // every read, so a replica whose renewals stall stops acting as leader
+    // before another one may take over.
+    held_until: AtomicU64,
+    leading: AtomicBool,
+}
+
+impl Leader {
+    pub fn new(election: Box<dyn Election>) -> Self {
+        Self {
+            election,
+            held_until: AtomicU64::new(0),
+            leading: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        unix_now_millis() < self.held_until.load(Relaxed)
+    }
+
+    pub fn observe(&self, node: &str) {
+        self.election.observe(node);
+    }
+
+    async fn campaign(&self, state: &AppState) {
+        let claimed_at = unix_now_millis();
+        let held_until = if self.election.campaign(&state.cluster).await {
+            claimed_at + LEASE_TTL.as_millis() as u64
+        } else {
+            // Someone else holds it; step down now rather than at expiry.
+            0
+        };
+        self.held_until.store(held_until, Relaxed);
+
+        let leading = self.is_leader();
+        if self.leading.swap(leading, Relaxed) != leading {
+            if leading {
+                info!("This replica is now the leader");
+            } else {
+                info!("This replica is no longer the leader");
+            }
+        }
+        state.metrics.leader.set(leading.into());
+    }
+}
+
+/// Claims the lease once, so a single replica leads from the start, then
+/// keeps renewing it in the background.
+pub async fn start(state: &Arc<AppState>) {
+    state.leader.campaign(state).await;
+
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut interval = interval(RENEW_INTERVAL);
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            state.leader.campaign(&state).await;
+        }
+    });
+}
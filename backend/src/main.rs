@@ -1,34 +1,83 @@
 use crate::{
+    answers::{answers_handler, approve_handler, reject_handler},
+    ballots::{ballots_handler, runoff_handler},
+    cluster::{Cluster, Gossip, InProcess, RedisPubSub, Seal, Tcp, Transport, TransportKind},
     config::Config,
+    embed::{embed_handler, oembed_handler, png_handler, svg_handler},
     error::AppError,
+    events::{events_handler, Events},
+    graphql::{graphql_handler, graphql_ws_handler},
+    leader::{Election, ElectionKind, FileLease, Leader, Peer, Single},
     metrics::{metrics_handler, Metrics},
+    poll::{unix_now, until, BallotMode},
+    polls::{poll_handler, vote_handler},
+    quiz::{leaderboard_handler, start_if_quiz, Quiz},
     save::{load, save},
+    session::Session,
     signals::shutdown_signal,
-    state::{AppState, Counters},
-    websocket::websocket_handler,
+    state::AppState,
+    store::Store,
+    throttle::Throttle,
+    webhooks::{
+        dead_letters_handler, subscribe_handler, unsubscribe_handler, webhooks_handler, Webhooks,
+    },
+    websocket::{announce_transition, broadcast_runoff, broadcast_wordcloud, websocket_handler},
 };
 use axum::{
     extract::State,
-    http::{header::CONTENT_TYPE, HeaderName, Method},
-    routing::get,
-    Router,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderName, Method,
+    },
+    routing::{delete, get, post},
+    Extension, Router,
 };
 use std::{
+    net::SocketAddr,
     sync::{atomic::AtomicUsize, Arc},
     time::Duration,
 };
-use tokio::{net::TcpListener, sync::broadcast, time::interval};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast,
+    time::{interval, sleep},
+};
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::{error, info};
+use tracing::{debug, error, info};
 use tracing_subscriber::{fmt, EnvFilter};
 
+mod admin;
+mod answers;
+mod ballots;
+mod chart;
+mod cluster;
 mod config;
+mod counters;
+mod crdt;
+mod embed;
 mod error;
+mod events;
+mod graphql;
+mod leader;
 mod metrics;
+mod poll;
+mod polls;
+mod quiz;
+#[cfg(test)]
+mod redis_standin;
+mod rpc;
 mod save;
+mod session;
 mod signals;
 mod state;
+mod store;
+mod throttle;
+mod webhooks;
 mod websocket;
+mod wordcloud;
+
+// Longest the scheduler sleeps before looking at the current poll again.
+const SCHEDULE_RECHECK: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
@@ -46,44 +95,209 @@ async fn main() -> Result<(), AppError> {
     info!("state_path = {}", config.state_path);
     info!("rust_port = {}", config.rust_port);
     info!("svelte_url = {}", config.svelte_url);
+    info!("outbound_high_water = {}", config.outbound_high_water);
+    info!("voter_rate = {}", config.voter_rate);
+    info!("address_rate = {}", config.address_rate);
+    info!("poll_ballot = {:?}", config.poll_ballot);
+    info!("poll_results = {:?}", config.poll_results);
+    info!("poll_privacy = {:?}", config.poll_privacy);
+    info!("poll_max_choices = {}", config.poll_max_choices);
+    info!("poll_max_score = {}", config.poll_max_score);
+    info!("poll_max_text_bytes = {}", config.poll_max_text_bytes);
+    info!("wordcloud_top_k = {}", config.wordcloud_top_k);
+    info!("quiz_correct = {}", config.quiz_correct);
+    info!("quiz_seconds = {}", config.quiz_seconds);
+    info!("poll_opens_at = {:?}", config.poll_opens_at);
+    info!("poll_closes_at = {:?}", config.poll_closes_at);
+    info!("created_polls = {}", config.created_polls);
+    info!("session_path = {:?}", config.session_path);
+    info!("admin_token set = {}", config.admin_token.is_some());
+    info!("webhook_key set = {}", config.webhook_key.is_some());
+    info!("cluster_transport = {:?}", config.cluster_transport);
+    info!("cluster_node = {}", config.cluster_node);
+    info!("cluster_port = {}", config.cluster_port);
+    info!("cluster_peers = {:?}", config.cluster_peers);
+    info!("cluster_secret set = {}", config.cluster_secret.is_some());
+    info!("counter_backend = {:?}", config.counter_backend);
+    info!("redis_url = {}", config.redis_url);
+    info!("leader_election = {:?}", config.leader_election);
+    info!("leader_lease_path = {}", config.leader_lease_path);
+    info!("api_origins = {:?}", config.api_origins);
+    info!("public_url = {}", config.public_url);
+
+    // Config refuses the shared transports without a secret.
+    let seal = || {
+        Seal::new(
+            config
+                .cluster_secret
+                .as_deref()
+                .expect("The cluster transport needs a secret"),
+        )
+    };
+    let transport: Box<dyn Transport> = match config.cluster_transport {
+        TransportKind::Memory => Box::new(InProcess::default()),
+        TransportKind::Tcp => {
+            Box::new(Tcp::start(config.cluster_port, config.cluster_peers.clone(), seal()).await?)
+        }
+        TransportKind::Redis => Box::new(RedisPubSub::start(&config.redis_url, seal()).await?),
+    };
+
+    let store = Store::connect(config.counter_backend, &config.redis_url).await?;
+
+    let election: Box<dyn Election> = match config.leader_election {
+        ElectionKind::Single => Box::new(Single),
+        ElectionKind::File => Box::new(FileLease::new(&config.leader_lease_path)),
+        ElectionKind::Peer => Box::new(Peer::default()),
+    };
 
     let (broadcast_tx, _) = broadcast::channel(100);
+    let (presenter_tx, _) = broadcast::channel(100);
+    let session = Session::load(&config, store)?;
+    let cluster = Cluster::new(&session.replica().node, transport);
     let state = Arc::new(AppState {
+        config: config.clone(),
+        quiz: Quiz::new(session.replica().clone()),
+        session,
         metrics: Metrics::default(),
-        counters: Counters::default(),
         concurrent_users: AtomicUsize::new(0),
         total_users: AtomicUsize::new(0),
         broadcast_tx,
+        presenter_tx,
+        cluster,
+        leader: Leader::new(election),
+        events: Events::default(),
+        webhooks: Webhooks::new(config.webhook_key.as_deref()),
+        throttle: Throttle::new(&config),
     });
 
     load(&config.state_path, State(state.clone()));
 
+    // The store outlives this process, so it may hold votes the save file
+    // missed, and the other way round.
+    for poll in state.session.polls() {
+        poll.counters.reconcile(&state.cluster.node).await?;
+    }
+
+    cluster::start(&state);
+    websocket::start(&state);
+    events::start(&state);
+    webhooks::start(&state);
+    throttle::start(&state);
+    leader::start(&state).await;
+
+    // Replicas share the state file, so only the leader writes it.
     let state_clone = state.clone();
     let state_path = config.state_path.clone();
     tokio::spawn(async move {
         let mut interval = interval(Duration::from_secs(60 * 30));
         loop {
             interval.tick().await;
+            if !state_clone.leader.is_leader() {
+                debug!("Not the leader, skipping save");
+                continue;
+            }
             if let Err(e) = save(&state_path, State(state_clone.clone())).await {
                 error!("Failed to save state: {}", e);
             }
         }
     });
 
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        // Polls keep their own schedules, so the wait is cut short in case
+        // the presenter moves on to a poll with an earlier one.
+        loop {
+            let next = state_clone.session.current().next_transition(unix_now());
+            sleep(next.map_or(SCHEDULE_RECHECK, until).min(SCHEDULE_RECHECK)).await;
+            // The other replicas announce it when the leader relays it.
+            if state_clone.leader.is_leader() && announce_transition(&state_clone) {
+                state_clone.cluster.publish(Gossip::Transition);
+            }
+        }
+    });
+
+    start_if_quiz(&state);
+
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let poll = state_clone.session.current();
+            // Every ballot marks the ledger dirty; only ranked polls have a runoff.
+            if poll.ballots.take_dirty() && poll.ballot == BallotMode::Ranked {
+                broadcast_runoff(&state_clone);
+            }
+            if poll.wordcloud.take_dirty() {
+                broadcast_wordcloud(&state_clone);
+            }
+        }
+    });
+
+    // Integrations like kiosks and chatbots call the poll API from their own
+    // origins; "*" allows any.
+    let svelte_url = config.svelte_url.clone();
+    let api_origins = config.api_origins.clone();
+    let api_cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _req| {
+            origin.as_bytes() == svelte_url.as_bytes()
+                || api_origins
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes())
+        }))
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .max_age(Duration::from_secs(60 * 60));
+
+    let api = Router::new()
+        .route("/api/polls/{index}", get(poll_handler))
+        .route("/api/polls/{index}/votes", post(vote_handler))
+        .layer(api_cors);
+
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::predicate(move |origin, _req| {
             origin.as_bytes() == config.svelte_url.as_bytes()
         }))
-        .allow_methods([Method::GET, Method::OPTIONS])
-        .allow_headers([CONTENT_TYPE, HeaderName::from_static("traceparent")])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            HeaderName::from_static("traceparent"),
+        ])
         .allow_credentials(true)
         .max_age(Duration::from_secs(60 * 60));
 
+    let graphql = post(graphql_handler);
+    #[cfg(debug_assertions)]
+    let graphql = graphql.get(graphql::playground_handler);
+
     let app = Router::new()
         .route("/api/ws", get(websocket_handler))
+        .route("/api/events", get(events_handler))
+        .route("/api/graphql", graphql)
+        .route("/api/graphql/ws", get(graphql_ws_handler))
+        .route("/api/runoff", get(runoff_handler))
+        .route("/api/quiz/leaderboard", get(leaderboard_handler))
+        .route("/api/admin/answers", get(answers_handler))
+        .route("/api/admin/answers/{id}/approve", post(approve_handler))
+        .route("/api/admin/answers/{id}/reject", post(reject_handler))
+        .route("/api/admin/polls/{index}/ballots", get(ballots_handler))
+        .route(
+            "/api/admin/webhooks",
+            get(webhooks_handler).post(subscribe_handler),
+        )
+        .route("/api/admin/webhooks/{id}", delete(unsubscribe_handler))
+        .route("/api/admin/webhooks/dead", get(dead_letters_handler))
+        .route("/embed/polls/{index}", get(embed_handler))
+        .route("/embed/polls/{index}/chart.svg", get(svg_handler))
+        .route("/embed/polls/{index}/chart.png", get(png_handler))
+        .route("/oembed", get(oembed_handler))
         .route("/metrics", get(metrics_handler))
+        .layer(Extension(graphql::schema(&state)))
         .layer(cors)
-        .with_state(state.clone());
+        .merge(api)
+        .with_state(state.clone())
+        .merge(rpc::router(&state));
 
     let addr = format!("0.0.0.0:{}", config.rust_port);
     info!("Binding to {}", addr);
@@ -91,12 +305,18 @@ async fn main() -> Result<(), AppError> {
     let listener = TcpListener::bind(&addr).await?;
     info!("Server running on {}", addr);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    // The throttle needs the address each connection comes from.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
-    if let Err(e) = save(&config.state_path, State(state.clone())).await {
-        error!("Failed to save state: {}", e);
+    if state.leader.is_leader() {
+        if let Err(e) = save(&config.state_path, State(state.clone())).await {
+            error!("Failed to save state: {}", e);
+        }
     }
     info!("Server shutdown complete");
     Ok(())
@@ -1,8 +1,12 @@
-use crate::{error::AppError, AppState};
+use crate::{
+    counters::{Snapshot, COLORS},
+    error::AppError,
+    AppState,
+};
 use axum::extract::State;
 use prometheus::{
-    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter,
-    IntCounterVec, IntGauge, Registry, TextEncoder,
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
 };
 use std::sync::Arc;
 use tracing::debug;
@@ -11,41 +15,111 @@ use tracing::debug;
 pub struct Metrics {
     pub concurrent_users: IntGauge,
     pub total_users: IntCounter,
-    pub votes: IntCounterVec,
+    pub votes: IntGaugeVec,
+    pub outbound_queue_depth: Histogram,
+    pub slow_consumers: IntCounter,
+    pub leader: IntGauge,
+    pub webhook_deliveries: IntCounterVec,
+    pub webhook_dead_letters: IntGauge,
     registry: Registry,
 }
 
+// Metrics only go into the struct's own registry, not the process-wide
+// default one, so more than one application state can run in a process.
 impl Default for Metrics {
     fn default() -> Self {
         let registry = Registry::new();
 
         let concurrent_users =
-            register_int_gauge!("concurrent_users", "Number of currently connected users")
+            IntGauge::new("concurrent_users", "Number of currently connected users")
                 .expect("Can't create concurrent_users metric");
 
-        let total_users =
-            register_int_counter!("total_users", "Total number of users since startup")
-                .expect("Can't create total_users metric");
+        let total_users = IntCounter::new("total_users", "Total number of users since startup")
+            .expect("Can't create total_users metric");
 
-        let votes = register_int_counter_vec!("votes", "Current vote counts", &["color"])
+        let votes = IntGaugeVec::new(Opts::new("votes", "Current vote counts"), &["color"])
             .expect("Can't create votes metric");
 
+        let outbound_queue_depth = Histogram::with_opts(
+            HistogramOpts::new(
+                "outbound_queue_depth",
+                "Messages already waiting in a connection's outbound queue when another is enqueued",
+            )
+            .buckets(
+                exponential_buckets(1.0, 2.0, 10)
+                    .expect("Can't create outbound_queue_depth buckets"),
+            ),
+        )
+        .expect("Can't create outbound_queue_depth metric");
+
+        let slow_consumers = IntCounter::new(
+            "slow_consumers",
+            "Connections closed for exceeding the outbound high-water mark",
+        )
+        .expect("Can't create slow_consumers metric");
+
+        let leader = IntGauge::new(
+            "leader",
+            "1 while this replica holds the leader lease, 0 otherwise",
+        )
+        .expect("Can't create leader metric");
+
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "webhook_deliveries",
+                "Webhook delivery attempts by outcome: delivered, failed or dead_lettered",
+            ),
+            &["outcome"],
+        )
+        .expect("Can't create webhook_deliveries metric");
+
+        let webhook_dead_letters = IntGauge::new(
+            "webhook_dead_letters",
+            "Webhook deliveries kept after failing every attempt",
+        )
+        .expect("Can't create webhook_dead_letters metric");
+
         registry
             .register(Box::new(concurrent_users.clone()))
             .unwrap();
         registry.register(Box::new(total_users.clone())).unwrap();
         registry.register(Box::new(votes.clone())).unwrap();
+        registry
+            .register(Box::new(outbound_queue_depth.clone()))
+            .unwrap();
+        registry.register(Box::new(slow_consumers.clone())).unwrap();
+        registry.register(Box::new(leader.clone())).unwrap();
+        registry
+            .register(Box::new(webhook_deliveries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(webhook_dead_letters.clone()))
+            .unwrap();
 
         Metrics {
             concurrent_users,
             total_users,
             votes,
+            outbound_queue_depth,
+            slow_consumers,
+            leader,
+            webhook_deliveries,
+            webhook_dead_letters,
             registry,
         }
     }
 }
 
 impl Metrics {
+    pub fn record_votes(&self, snapshot: &Snapshot) {
+        for color in COLORS {
+            let count = snapshot.get(color).unwrap_or_default();
+            self.votes
+                .with_label_values(&[color])
+                .set(count.try_into().unwrap_or(i64::MAX));
+        }
+    }
+
     pub fn gather(&self) -> Result<String, AppError> {
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -57,5 +131,8 @@ impl Metrics {
 
 pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> Result<String, AppError> {
     debug!("Metrics being scrapped");
+    state
+        .metrics
+        .record_votes(&state.session.current().counters.snapshot());
     state.metrics.gather()
 }
@@ -0,0 +1,316 @@
+use crate::{
+    answers::Answers,
+    ballots::Ballots,
+    cluster::Replica,
+    config::MAX_BYTES,
+    counters::COLORS,
+    crdt::{Register, Stamp},
+    store::CounterStore,
+    wordcloud::WordCloud,
+};
+use async_graphql::Enum;
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum PollStatus {
+    Scheduled,
+    Open,
+    Closed,
+}
+
+impl PollStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Scheduled,
+            1 => Self::Open,
+            _ => Self::Closed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum BallotMode {
+    Single,
+    Ranked,
+    Approval,
+    Multi,
+    Score,
+    Text,
+    Quiz,
+}
+
+impl FromStr for BallotMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "single" => Ok(Self::Single),
+            "ranked" => Ok(Self::Ranked),
+            "approval" => Ok(Self::Approval),
+            "multi" => Ok(Self::Multi),
+            "score" => Ok(Self::Score),
+            "text" => Ok(Self::Text),
+            "quiz" => Ok(Self::Quiz),
+            _ => Err(()),
+        }
+    }
+}
+
+// Who sees the counts while votes come in. Presenters and admins always do;
+// everyone else still sees how many ballots were cast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Live,
+    // Revealed once the poll closes on schedule. Locking only pauses voting.
+    AfterClose,
+    AdminsOnly,
+}
+
+impl FromStr for Visibility {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "live" => Ok(Self::Live),
+            "after_close" => Ok(Self::AfterClose),
+            "admins_only" => Ok(Self::AdminsOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+// Whether ballots and answers are kept with who cast them, so admins can
+// download them, or only counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+    Anonymous,
+    Attributed,
+}
+
+impl FromStr for Privacy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "anonymous" => Ok(Self::Anonymous),
+            "attributed" => Ok(Self::Attributed),
+            _ => Err(()),
+        }
+    }
+}
+
+// How one poll is run, from the environment or an entry of the session file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollDefinition {
+    pub title: Option<String>,
+    pub ballot: BallotMode,
+    pub results: Visibility,
+    pub privacy: Privacy,
+    pub max_choices: usize,
+    pub max_score: usize,
+    pub max_text_bytes: usize,
+    // Index of the right option and the countdown length for quiz polls.
+    pub correct: usize,
+    pub seconds: u64,
+    // Unix seconds. Saves from before polls had their own schedule leave
+    // them out.
+    #[serde(default)]
+    pub opens_at: Option<u64>,
+    #[serde(default)]
+    pub closes_at: Option<u64>,
+}
+
+/// A presenter setting of a poll. Reads don't lock; writes are stamped so
+/// replicas agree on the last one.
+#[derive(Default)]
+pub struct Switch {
+    on: AtomicBool,
+    stamp: Mutex<Stamp>,
+}
+
+impl Switch {
+    pub fn is_on(&self) -> bool {
+        self.on.load(Relaxed)
+    }
+
+    pub fn set(&self, on: bool, node: &str) {
+        let mut stamp = self.stamp.lock().unwrap_or_else(|e| e.into_inner());
+        *stamp = Stamp::after(Some(&stamp), node);
+        self.on.store(on, Relaxed);
+    }
+
+    pub fn shared(&self) -> Register<bool> {
+        let stamp = self.stamp.lock().unwrap_or_else(|e| e.into_inner());
+        Register {
+            value: self.is_on(),
+            stamp: stamp.clone(),
+        }
+    }
+
+    /// Takes another replica's setting if it was written later. Returns
+    /// whether the setting changed.
+    pub fn merge(&self, shared: &Register<bool>) -> bool {
+        let mut stamp = self.stamp.lock().unwrap_or_else(|e| e.into_inner());
+        if shared.stamp <= *stamp {
+            return false;
+        }
+        *stamp = shared.stamp.clone();
+        self.on.swap(shared.value, Relaxed) != shared.value
+    }
+}
+
+// Status is a pure function of the clock; `announced` only remembers what the
+// scheduler last broadcast so it can tell when a boundary has been crossed.
+pub struct Poll {
+    pub title: Option<String>,
+    pub ballot: BallotMode,
+    pub results: Visibility,
+    pub privacy: Privacy,
+    // Most options one selection ballot may pick; every option for approval.
+    pub max_choices: usize,
+    // Highest score a score ballot may give one option.
+    pub max_score: usize,
+    // Longest plain text frame accepted: a color name, or a text answer.
+    pub max_bytes: usize,
+    pub correct: usize,
+    pub seconds: u64,
+    pub opens_at: Option<u64>,
+    pub closes_at: Option<u64>,
+    pub counters: Box<dyn CounterStore>,
+    pub ballots: Ballots,
+    pub answers: Answers,
+    pub wordcloud: WordCloud,
+    // Set by the presenter: no new votes while locked, and the audience only
+    // sees participation while hidden, whatever `results` says.
+    pub locked: Switch,
+    pub hidden: Switch,
+    // Set while a quiz question runs, so answer counts can't give the right
+    // option away before the leaderboard does.
+    pub answering: AtomicBool,
+    announced: AtomicU8,
+}
+
+impl Poll {
+    pub fn new(
+        definition: PollDefinition,
+        wordcloud_top_k: usize,
+        replica: &Replica,
+        counters: Box<dyn CounterStore>,
+    ) -> Self {
+        let ballot = definition.ballot;
+        let max_choices = match ballot {
+            BallotMode::Multi => definition.max_choices.min(COLORS.len()),
+            BallotMode::Approval => COLORS.len(),
+            BallotMode::Single
+            | BallotMode::Ranked
+            | BallotMode::Score
+            | BallotMode::Text
+            | BallotMode::Quiz => 1,
+        };
+        let max_bytes = match ballot {
+            BallotMode::Text => definition.max_text_bytes,
+            _ => MAX_BYTES.into(),
+        };
+        let poll = Self {
+            title: definition.title,
+            ballot,
+            results: definition.results,
+            privacy: definition.privacy,
+            max_choices,
+            max_score: definition.max_score,
+            max_bytes,
+            correct: definition.correct,
+            seconds: definition.seconds,
+            opens_at: definition.opens_at,
+            closes_at: definition.closes_at,
+            counters,
+            ballots: Ballots::new(definition.privacy, ballot, replica.clone()),
+            answers: Answers::new(replica.clone()),
+            wordcloud: WordCloud::new(wordcloud_top_k),
+            locked: Switch::default(),
+            hidden: Switch::default(),
+            answering: AtomicBool::new(false),
+            announced: AtomicU8::new(0),
+        };
+        poll.announced.store(poll.status() as u8, Relaxed);
+        poll
+    }
+
+    pub fn status(&self) -> PollStatus {
+        self.status_at(unix_now())
+    }
+
+    pub fn status_at(&self, now: u64) -> PollStatus {
+        if self.closes_at.is_some_and(|closes_at| now >= closes_at) {
+            PollStatus::Closed
+        } else if self.opens_at.is_some_and(|opens_at| now < opens_at) {
+            PollStatus::Scheduled
+        } else {
+            PollStatus::Open
+        }
+    }
+
+    /// Whether the audience may see the counts right now.
+    pub fn results_visible(&self) -> bool {
+        if self.hidden.is_on() || self.answering.load(Relaxed) {
+            return false;
+        }
+        match self.results {
+            Visibility::Live => true,
+            Visibility::AfterClose => self.status() == PollStatus::Closed,
+            Visibility::AdminsOnly => false,
+        }
+    }
+
+    /// The next open or close time still ahead of `now`, if any.
+    pub fn next_transition(&self, now: u64) -> Option<u64> {
+        [self.opens_at, self.closes_at]
+            .into_iter()
+            .flatten()
+            .filter(|at| *at > now)
+            .min()
+    }
+
+    /// Returns the current status if it differs from the last one announced.
+    pub fn transition(&self) -> Option<PollStatus> {
+        let status = self.status();
+        let previous = self.announced.swap(status as u8, Relaxed);
+        (PollStatus::from_u8(previous) != status).then_some(status)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+pub fn unix_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+pub fn until(at: u64) -> Duration {
+    (UNIX_EPOCH + Duration::from_secs(at))
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+}
+
+pub fn until_millis(at: u64) -> Duration {
+    (UNIX_EPOCH + Duration::from_millis(at))
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+}
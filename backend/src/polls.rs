@@ -0,0 +1,227 @@
+use crate::{
+    admin::Admin,
+    cluster::Gossip,
+    counters::Snapshot,
+    error::AppError,
+    poll::{BallotMode, Poll},
+    session::SessionEntry,
+    state::AppState,
+    throttle::ClientIp,
+    websocket::{broadcast_poll, process_request, valid_voter, THROTTLED},
+};
+use axum::{
+    extract::{ws::Message, Path, Query, State},
+    Json,
+};
+use futures_util::{stream, Stream};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{net::IpAddr, sync::Arc};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+
+#[derive(Deserialize)]
+pub struct VoteQuery {
+    // Required unless presenting: an id the client keeps, such as a UUID,
+    // so its requests are throttled together and reusing it changes or
+    // retracts that ballot.
+    voter: Option<String>,
+    name: Option<String>,
+}
+
+pub fn find(state: &AppState, index: usize) -> Result<&Poll, AppError> {
+    state
+        .session
+        .polls()
+        .get(index)
+        .ok_or_else(|| AppError::NotFound(format!("No poll {index} in the session")))
+}
+
+// A poll's counts, zeroed apart from the number of ballots while the
+// audience may not see them.
+pub fn shown(poll: &Poll, privileged: bool) -> Snapshot {
+    let snapshot = poll.counters.snapshot();
+    if privileged || poll.results_visible() {
+        snapshot
+    } else {
+        Snapshot {
+            ballots: snapshot.ballots,
+            ..Snapshot::default()
+        }
+    }
+}
+
+pub fn tallies(state: &AppState, index: usize, poll: &Poll, privileged: bool) -> Value {
+    let shown = shown(poll, privileged);
+    json!({
+        "poll": index,
+        "current": index == state.session.index(),
+        "title": poll.title,
+        "ballot": poll.ballot,
+        "status": poll.status(),
+        "locked": poll.locked.is_on(),
+        "hidden": !poll.results_visible(),
+        "red": shown.red,
+        "green": shown.green,
+        "blue": shown.blue,
+        "purple": shown.purple,
+        "total": shown.total,
+        "ballots": shown.ballots,
+        "averages": (poll.ballot == BallotMode::Score).then(|| shown.averages()),
+    })
+}
+
+/// Follows poll `requested`, or whichever poll is current, yielding `view`
+/// of it now and again whenever it changes. Every frame the audience is sent
+/// may have changed it; privileged viewers follow the presenters' frames,
+/// which include hidden results.
+pub fn watch<T, F>(
+    state: &Arc<AppState>,
+    requested: Option<usize>,
+    privileged: bool,
+    view: F,
+) -> impl Stream<Item = T> + Send + 'static
+where
+    T: PartialEq + Clone + Send + 'static,
+    F: Fn(&AppState, usize, &Poll) -> T + Send + 'static,
+{
+    let rx = if privileged {
+        state.presenter_tx.subscribe()
+    } else {
+        state.broadcast_tx.subscribe()
+    };
+    let state = Arc::clone(state);
+    stream::unfold((rx, None, view), move |(mut rx, last, view)| {
+        let state = Arc::clone(&state);
+        async move {
+            loop {
+                if last.is_some() {
+                    match rx.recv().await {
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+                let index = requested.unwrap_or_else(|| state.session.index());
+                let poll = state.session.polls().get(index)?;
+                let current = view(&state, index, poll);
+                if last.as_ref() != Some(&current) {
+                    return Some((current.clone(), (rx, Some(current), view)));
+                }
+            }
+        }
+    })
+}
+
+/// Adds a poll, described like an entry of the session file, at the end of
+/// the session here and on the other replicas.
+pub fn create(state: &AppState, entry: SessionEntry) -> Result<usize, AppError> {
+    let definition = entry
+        .definition(&state.config.poll_definition())
+        .map_err(AppError::BadRequest)?;
+    let index = state.session.create(definition.clone(), &state.config)?;
+    info!("Poll {} created", index);
+    state.cluster.publish(Gossip::Created {
+        poll: index,
+        definition,
+    });
+    // Presenters see how many polls the session has.
+    broadcast_poll(state);
+    Ok(index)
+}
+
+/// Casts `message` on poll `index`, which has to be the current one, the way
+/// a websocket frame from `address` would be. Returns the voter id and the
+/// replies, or the reason the vote was refused.
+pub async fn cast(
+    state: &Arc<AppState>,
+    index: usize,
+    message: &str,
+    voter: Option<String>,
+    name: Option<String>,
+    presenter: bool,
+    address: IpAddr,
+) -> Result<(Arc<str>, Vec<Value>), AppError> {
+    find(state, index)?;
+    if index != state.session.index() {
+        return Err(AppError::Conflict(format!(
+            "Poll {index} is not the current poll"
+        )));
+    }
+
+    // A fresh id per request would dodge the voter's allowance and leave
+    // ballots nobody can change.
+    if !presenter {
+        let Some(voter) = voter.as_deref().filter(|voter| valid_voter(voter)) else {
+            return Err(AppError::BadRequest(
+                "A voter id of letters, digits, - and _ is required".into(),
+            ));
+        };
+        if !state.throttle.allow(address, voter) {
+            return Err(AppError::TooManyRequests(THROTTLED.into()));
+        }
+    }
+
+    let (voter, replies) = process_request(message, voter, name, presenter, address, state).await;
+
+    let mut acknowledged = Vec::new();
+    for reply in replies {
+        match reply {
+            Message::Text(text) => {
+                let reply: Value = serde_json::from_str(&text)?;
+                if reply["type"] == "error" {
+                    let reason = reply["message"].as_str().unwrap_or("Rejected");
+                    return Err(AppError::BadRequest(reason.to_string()));
+                }
+                acknowledged.push(reply);
+            }
+            Message::Close(frame) => {
+                let reason = frame.map_or_else(|| "Rejected".to_string(), |f| f.reason.to_string());
+                return Err(AppError::BadRequest(reason));
+            }
+            _ => {}
+        }
+    }
+    Ok((voter, acknowledged))
+}
+
+/// The current tallies of a poll, for clients that can't keep a websocket.
+pub async fn poll_handler(
+    admin: Option<Admin>,
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<Json<Value>, AppError> {
+    debug!("Poll {} requested", index);
+    let poll = find(&state, index)?;
+    Ok(Json(tallies(&state, index, poll, admin.is_some())))
+}
+
+/// Casts the body as a vote on the current poll. It takes the same form as
+/// a websocket frame: an option name, a JSON ballot, an answer or, with the
+/// admin token, a presenter command.
+pub async fn vote_handler(
+    admin: Option<Admin>,
+    ClientIp(address): ClientIp,
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+    Query(query): Query<VoteQuery>,
+    body: String,
+) -> Result<Json<Value>, AppError> {
+    let message = body.trim_end_matches(['\r', '\n']);
+    let (voter, replies) = cast(
+        &state,
+        index,
+        message,
+        query.voter,
+        query.name,
+        admin.is_some(),
+        address,
+    )
+    .await?;
+    let poll = find(&state, index)?;
+
+    Ok(Json(json!({
+        "voter": &*voter,
+        "replies": replies,
+        "results": tallies(&state, index, poll, admin.is_some()),
+    })))
+}
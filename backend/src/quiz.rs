@@ -0,0 +1,537 @@
+use crate::{
+    cluster::Replica,
+    config::MAX_QUIZ_SECONDS,
+    counters::COLORS,
+    poll::{unix_now_millis, until_millis, BallotMode, Poll, PollStatus},
+    state::AppState,
+    websocket::{broadcast_leaderboard, broadcast_question, broadcast_update},
+};
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    mem,
+    sync::{atomic::Ordering::Relaxed, Arc, Mutex},
+};
+use tokio::time::sleep;
+use tracing::{debug, info};
+
+pub const LEADERBOARD_SIZE: usize = 10;
+
+// A correct answer scores between half and all of this, depending on how
+// much of the countdown was left.
+const MAX_POINTS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Question {
+    pub number: u32,
+    // Index of the quiz poll in the session.
+    pub poll: usize,
+    // Unix milliseconds.
+    pub started_at: u64,
+    pub deadline: u64,
+}
+
+// Leaderboards show a player number derived from the participant's voter id
+// rather than the id itself, which would let others act as them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Standing {
+    pub player: u32,
+    pub name: Option<String>,
+    pub score: u64,
+    pub correct: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Scored {
+    // Unix milliseconds.
+    at: u64,
+    points: u64,
+}
+
+// A participant's answers by quiz poll, so replicas that scored different
+// answers of theirs can add them up without counting one twice.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Player {
+    name: Option<String>,
+    #[serde(default)]
+    named_at: u64,
+    #[serde(default)]
+    answers: BTreeMap<usize, Scored>,
+    // Score and correct answers from saves that predate per-poll answers.
+    #[serde(default)]
+    carried_score: u64,
+    #[serde(default)]
+    carried_correct: u32,
+}
+
+impl Player {
+    fn score(&self) -> u64 {
+        self.carried_score
+            + self
+                .answers
+                .values()
+                .map(|scored| scored.points)
+                .sum::<u64>()
+    }
+
+    fn correct(&self) -> u32 {
+        self.carried_correct
+            + self
+                .answers
+                .values()
+                .filter(|scored| scored.points > 0)
+                .count() as u32
+    }
+
+    fn joined_at(&self) -> u64 {
+        self.answers
+            .values()
+            .map(|scored| scored.at)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // Each poll keeps the earliest answer and the name the latest one.
+    fn merge(&mut self, other: Player) {
+        if other.named_at > self.named_at {
+            self.name = other.name;
+            self.named_at = other.named_at;
+        }
+        for (poll, scored) in other.answers {
+            self.answers
+                .entry(poll)
+                .and_modify(|known| {
+                    if scored.at < known.at {
+                        *known = scored;
+                    }
+                })
+                .or_insert(scored);
+        }
+        self.carried_score = self.carried_score.max(other.carried_score);
+        self.carried_correct = self.carried_correct.max(other.carried_correct);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedStanding {
+    voter: String,
+    #[serde(flatten)]
+    player: Player,
+    // Totals written by older saves.
+    #[serde(default, skip_serializing)]
+    score: u64,
+    #[serde(default, skip_serializing)]
+    correct: u32,
+}
+
+struct Round {
+    question: Question,
+    correct: usize,
+    finished: bool,
+}
+
+// Standings carry over from one quiz poll of the session to the next; only
+// one question runs at a time. A participant scores once per quiz poll, even
+// if the poll's question runs again, and replicas share the answers scored.
+pub struct Quiz {
+    round: Mutex<Option<Round>>,
+    players: Mutex<HashMap<Arc<str>, Player>>,
+    // Voters who answered here since the players were last shared.
+    unshared: Mutex<HashSet<Arc<str>>>,
+    replica: Replica,
+}
+
+impl Quiz {
+    pub fn new(replica: Replica) -> Self {
+        Self {
+            round: Mutex::default(),
+            players: Mutex::default(),
+            unshared: Mutex::default(),
+            replica,
+        }
+    }
+
+    // The same on every replica, since they share the key.
+    fn player_number(&self, voter: &str) -> u32 {
+        let digest = self.replica.digest(voter);
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000 + 1
+    }
+
+    fn standing(&self, voter: &str, player: &Player) -> Standing {
+        Standing {
+            player: self.player_number(voter),
+            name: player.name.clone(),
+            score: player.score(),
+            correct: player.correct(),
+        }
+    }
+
+    /// Starts the countdown for the quiz poll at `index` of the session.
+    pub fn start(&self, index: usize, poll: &Poll) -> Question {
+        let mut round = self.round.lock().unwrap_or_else(|e| e.into_inner());
+        let started_at = unix_now_millis();
+        // Definitions are checked when made, but not those from saves and
+        // other replicas, so an outsized one is cut short rather than wrap.
+        let window = poll.seconds.min(MAX_QUIZ_SECONDS).saturating_mul(1000);
+        let question = Question {
+            number: round.as_ref().map_or(1, |round| round.question.number + 1),
+            poll: index,
+            started_at,
+            deadline: started_at.saturating_add(window),
+        };
+        *round = Some(Round {
+            question,
+            correct: poll.correct,
+            finished: false,
+        });
+        question
+    }
+
+    /// The question running for, or last run by, the poll at `index`.
+    pub fn current(&self, index: usize) -> Option<Question> {
+        self.round
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|round| round.question)
+            .filter(|question| question.poll == index)
+    }
+
+    /// Whether the question last run by the poll at `index` is over, so its
+    /// leaderboard is out.
+    pub fn finished(&self, index: usize) -> bool {
+        self.round
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .is_some_and(|round| round.finished && round.question.poll == index)
+    }
+
+    /// Scores a participant's only answer to the running question, returning
+    /// their player number.
+    pub fn answer(
+        &self,
+        voter: &Arc<str>,
+        name: Option<&str>,
+        option: usize,
+    ) -> Result<u32, &'static str> {
+        self.answer_at(voter, name, option, unix_now_millis())
+    }
+
+    fn answer_at(
+        &self,
+        voter: &Arc<str>,
+        name: Option<&str>,
+        option: usize,
+        now: u64,
+    ) -> Result<u32, &'static str> {
+        let mut round = self.round.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(round) = round.as_mut() else {
+            return Err("Question has not started yet");
+        };
+        if round.finished || now >= round.question.deadline {
+            return Err("Time is up for this question");
+        }
+        let mut players = self.players.lock().unwrap_or_else(|e| e.into_inner());
+        let player = players.entry(Arc::clone(voter)).or_default();
+        let Entry::Vacant(entry) = player.answers.entry(round.question.poll) else {
+            return Err("Question already answered");
+        };
+
+        let points = if option == round.correct {
+            let window = round.question.deadline - round.question.started_at;
+            let remaining = round.question.deadline - now;
+            MAX_POINTS / 2 + MAX_POINTS * remaining / window.max(1) / 2
+        } else {
+            0
+        };
+        entry.insert(Scored { at: now, points });
+        if let Some(name) = name {
+            player.name = Some(name.to_string());
+            player.named_at = now;
+        }
+        self.unshared
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(Arc::clone(voter));
+
+        Ok(self.player_number(voter))
+    }
+
+    /// Closes question `number`. `false` if it was already closed or a later
+    /// question has started since.
+    pub fn finish(&self, number: u32) -> bool {
+        let mut round = self.round.lock().unwrap_or_else(|e| e.into_inner());
+        match round.as_mut() {
+            Some(round) if round.question.number == number && !round.finished => {
+                round.finished = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Standings by score, then correct answers, then who joined first.
+    pub fn leaderboard(&self, limit: usize) -> Vec<Standing> {
+        let players = self.players.lock().unwrap_or_else(|e| e.into_inner());
+        let mut leaderboard: Vec<(u64, Standing)> = players
+            .iter()
+            .map(|(voter, player)| (player.joined_at(), self.standing(voter, player)))
+            .collect();
+        leaderboard.sort_unstable_by(|(a_joined, a), (b_joined, b)| {
+            b.score
+                .cmp(&a.score)
+                .then(b.correct.cmp(&a.correct))
+                .then(a_joined.cmp(b_joined))
+                .then(a.player.cmp(&b.player))
+        });
+        leaderboard
+            .into_iter()
+            .take(limit)
+            .map(|(_, standing)| standing)
+            .collect()
+    }
+
+    pub fn saved(&self) -> Vec<SavedStanding> {
+        self.players
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(voter, player)| SavedStanding {
+                voter: voter.to_string(),
+                player: player.clone(),
+                score: 0,
+                correct: 0,
+            })
+            .collect()
+    }
+
+    pub fn restore(&self, saved: &[SavedStanding]) {
+        *self.players.lock().unwrap_or_else(|e| e.into_inner()) = saved
+            .iter()
+            .map(|saved| {
+                let mut player = saved.player.clone();
+                player.carried_score += saved.score;
+                player.carried_correct += saved.correct;
+                (saved.voter.as_str().into(), player)
+            })
+            .collect();
+    }
+
+    /// Players for the other replicas: all of them on a full sync,
+    /// otherwise those who answered here since the last call.
+    pub fn share(&self, full: bool) -> Vec<SavedStanding> {
+        let unshared = mem::take(&mut *self.unshared.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut shared = self.saved();
+        if !full {
+            shared.retain(|standing| unshared.contains(standing.voter.as_str()));
+        }
+        shared
+    }
+
+    pub fn merge(&self, shared: Vec<SavedStanding>) {
+        let mut players = self.players.lock().unwrap_or_else(|e| e.into_inner());
+        for standing in shared {
+            players
+                .entry(standing.voter.into())
+                .or_default()
+                .merge(standing.player);
+        }
+    }
+}
+
+/// Starts a question if the session is on a quiz poll that is open.
+pub fn start_if_quiz(state: &Arc<AppState>) {
+    let poll = state.session.current();
+    if poll.ballot == BallotMode::Quiz && poll.status() == PollStatus::Open {
+        tokio::spawn(run_question(Arc::clone(state)));
+    }
+}
+
+// Announces the question, waits out the countdown and broadcasts the
+// answer counts along with the leaderboard. Until then the audience only
+// sees how many have answered.
+async fn run_question(state: Arc<AppState>) {
+    let index = state.session.index();
+    let Some(poll) = state.session.polls().get(index) else {
+        return;
+    };
+    poll.answering.store(true, Relaxed);
+    let question = state.quiz.start(index, poll);
+    info!("Quiz question {} started", question.number);
+    broadcast_question(&state, &question);
+
+    sleep(until_millis(question.deadline)).await;
+
+    let finished = state.quiz.finish(question.number);
+    // A later question for the same poll keeps the counts hidden until it
+    // is over too.
+    if state
+        .quiz
+        .current(index)
+        .is_none_or(|current| current.number == question.number)
+    {
+        poll.answering.store(false, Relaxed);
+    }
+    if finished {
+        info!(
+            "Quiz question {} finished, correct answer was {}",
+            question.number, COLORS[poll.correct]
+        );
+        if index == state.session.index() {
+            broadcast_update(&COLORS, poll.counters.snapshot(), poll, &state);
+        }
+        broadcast_leaderboard(&state, &question, poll.correct);
+    }
+}
+
+pub async fn leaderboard_handler(State(state): State<Arc<AppState>>) -> Json<Vec<Standing>> {
+    debug!("Quiz leaderboard requested");
+    Json(state.quiz.leaderboard(usize::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const STARTED_AT: u64 = 1_000_000;
+
+    // A quiz running question 1 for poll 0, which began at `STARTED_AT` and
+    // lasts `seconds`, with the first option correct.
+    fn running(seconds: u64) -> Quiz {
+        let quiz = Quiz::new(Replica::new(&Config::test("a")));
+        ask(&quiz, 0, seconds);
+        quiz
+    }
+
+    fn ask(quiz: &Quiz, poll: usize, seconds: u64) {
+        let mut round = quiz.round.lock().unwrap();
+        let number = round.as_ref().map_or(1, |round| round.question.number + 1);
+        *round = Some(Round {
+            question: Question {
+                number,
+                poll,
+                started_at: STARTED_AT,
+                deadline: STARTED_AT + seconds * 1000,
+            },
+            correct: 0,
+            finished: false,
+        });
+    }
+
+    fn score(quiz: &Quiz, voter: &str) -> u64 {
+        quiz.players.lock().unwrap()[voter].score()
+    }
+
+    #[test]
+    fn correct_answers_score_by_time_left() {
+        let quiz = running(20);
+        let at = |voter: &str, millis: u64| {
+            let voter = Arc::from(voter);
+            quiz.answer_at(&voter, None, 0, STARTED_AT + millis)
+                .unwrap();
+            score(&quiz, &voter)
+        };
+
+        assert_eq!(at("start", 0), MAX_POINTS);
+        assert_eq!(at("halfway", 10_000), MAX_POINTS * 3 / 4);
+        assert_eq!(at("last moment", 19_999), MAX_POINTS / 2);
+    }
+
+    #[test]
+    fn wrong_answers_score_nothing() {
+        let quiz = running(20);
+        let voter = Arc::from("wrong");
+
+        quiz.answer_at(&voter, None, 2, STARTED_AT).unwrap();
+        assert_eq!(score(&quiz, &voter), 0);
+        assert_eq!(quiz.leaderboard(1)[0].correct, 0);
+    }
+
+    #[test]
+    fn late_answers_are_refused() {
+        let quiz = running(20);
+        let voter = Arc::from("late");
+
+        assert_eq!(
+            quiz.answer_at(&voter, None, 0, STARTED_AT + 20_000),
+            Err("Time is up for this question")
+        );
+        assert_eq!(
+            quiz.answer_at(&voter, None, 0, STARTED_AT + 60_000),
+            Err("Time is up for this question")
+        );
+        assert!(quiz.finish(1));
+        assert_eq!(
+            quiz.answer_at(&voter, None, 0, STARTED_AT),
+            Err("Time is up for this question")
+        );
+        assert!(quiz.leaderboard(LEADERBOARD_SIZE).is_empty());
+    }
+
+    #[test]
+    fn a_zero_second_window_takes_no_answers() {
+        let quiz = running(0);
+
+        assert_eq!(
+            quiz.answer_at(&Arc::from("eager"), None, 0, STARTED_AT),
+            Err("Time is up for this question")
+        );
+    }
+
+    #[test]
+    fn only_the_first_answer_counts() {
+        let quiz = running(20);
+        let voter = Arc::from("twice");
+
+        quiz.answer_at(&voter, None, 1, STARTED_AT).unwrap();
+        assert_eq!(
+            quiz.answer_at(&voter, None, 0, STARTED_AT + 1),
+            Err("Question already answered")
+        );
+        assert_eq!(score(&quiz, &voter), 0);
+
+        // Running the poll's question again does not give a second chance.
+        ask(&quiz, 0, 20);
+        assert_eq!(
+            quiz.answer_at(&voter, None, 0, STARTED_AT),
+            Err("Question already answered")
+        );
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_score_then_correct_then_joining() {
+        let quiz = running(20);
+        let answer = |voter: &str, option, millis| {
+            quiz.answer_at(&Arc::from(voter), Some(voter), option, STARTED_AT + millis)
+                .unwrap()
+        };
+        // Poll 0: one at full marks, one at half, and two wrong in turn.
+        answer("fast", 0, 0);
+        answer("slow", 0, 19_999);
+        answer("early miss", 1, 100);
+        answer("late miss", 1, 200);
+        // Poll 1: the slow player catches up on score with more correct.
+        ask(&quiz, 1, 20);
+        answer("slow", 0, 19_999);
+        answer("fast", 1, 0);
+
+        let names: Vec<_> = quiz
+            .leaderboard(LEADERBOARD_SIZE)
+            .into_iter()
+            .map(|standing| (standing.name.unwrap(), standing.score, standing.correct))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("slow".into(), MAX_POINTS, 2),
+                ("fast".into(), MAX_POINTS, 1),
+                ("early miss".into(), 0, 0),
+                ("late miss".into(), 0, 0),
+            ]
+        );
+        assert_eq!(quiz.leaderboard(2).len(), 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! A small in-memory server speaking enough of the Redis protocol (RESP2)
+//! for tests of the Redis counter store and gossip transport, so they run
+//! without installing Redis.
+//!
+//! Supports PING, SELECT, CLIENT, GET, INCR, INCRBY, HGET, HSET, HINCRBY,
+//! HGETALL, DEL, MULTI/EXEC/DISCARD, PUBLISH and SUBSCRIBE. Data is lost when
+//! it stops.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+
+#[derive(Default)]
+struct Data {
+    strings: HashMap<Vec<u8>, i64>,
+    hashes: HashMap<Vec<u8>, HashMap<Vec<u8>, i64>>,
+}
+
+#[derive(Clone)]
+struct Server {
+    data: Arc<Mutex<Data>>,
+    messages: broadcast::Sender<(Vec<u8>, Vec<u8>)>,
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend(format!("+{status}\r\n").as_bytes()),
+            Reply::Error(message) => out.extend(format!("-ERR {message}\r\n").as_bytes()),
+            Reply::Integer(value) => out.extend(format!(":{value}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend(bytes);
+                out.extend(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    fn bulk(bytes: &[u8]) -> Self {
+        Reply::Bulk(Some(bytes.to_vec()))
+    }
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| Reply::Error("value is not an integer or out of range".into()))
+}
+
+impl Server {
+    // Commands that only touch the data, so they can also run inside MULTI.
+    fn execute(&self, data: &mut Data, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let result = match (name.as_str(), &args[1..]) {
+            ("PING", []) => Ok(Reply::Status("PONG")),
+            ("PING", [message]) => Ok(Reply::bulk(message)),
+            ("SELECT" | "CLIENT", _) => Ok(Reply::Status("OK")),
+            ("GET", [key]) => Ok(Reply::Bulk(
+                data.strings
+                    .get(key)
+                    .map(|value| value.to_string().into_bytes()),
+            )),
+            ("INCR", [key]) => {
+                let value = data.strings.entry(key.clone()).or_default();
+                *value += 1;
+                Ok(Reply::Integer(*value))
+            }
+            ("INCRBY", [key, by]) => integer(by).map(|by| {
+                let value = data.strings.entry(key.clone()).or_default();
+                *value += by;
+                Reply::Integer(*value)
+            }),
+            ("HGET", [key, field]) => Ok(Reply::Bulk(
+                data.hashes
+                    .get(key)
+                    .and_then(|hash| hash.get(field))
+                    .map(|value| value.to_string().into_bytes()),
+            )),
+            ("HSET", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => pairs
+                .chunks(2)
+                .map(|pair| integer(&pair[1]).map(|value| (pair[0].clone(), value)))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|pairs| {
+                    let hash = data.hashes.entry(key.clone()).or_default();
+                    let added = pairs
+                        .into_iter()
+                        .filter(|(field, value)| hash.insert(field.clone(), *value).is_none())
+                        .count();
+                    Reply::Integer(added as i64)
+                }),
+            ("HINCRBY", [key, field, by]) => integer(by).map(|by| {
+                let value = data
+                    .hashes
+                    .entry(key.clone())
+                    .or_default()
+                    .entry(field.clone())
+                    .or_default();
+                *value += by;
+                Reply::Integer(*value)
+            }),
+            ("HGETALL", [key]) => Ok(Reply::Array(
+                data.hashes
+                    .get(key)
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|(field, value)| {
+                        [
+                            Reply::bulk(field),
+                            Reply::bulk(value.to_string().as_bytes()),
+                        ]
+                    })
+                    .collect(),
+            )),
+            ("DEL", keys) => Ok(Reply::Integer(
+                keys.iter()
+                    .filter(|&key| {
+                        data.strings.remove(key).is_some() | data.hashes.remove(key).is_some()
+                    })
+                    .count() as i64,
+            )),
+            ("PUBLISH", [channel, message]) => Ok(Reply::Integer(
+                self.messages
+                    .send((channel.clone(), message.clone()))
+                    .unwrap_or(0) as i64,
+            )),
+            _ => Err(Reply::Error(format!("unsupported command '{name}'"))),
+        };
+        result.unwrap_or_else(|error| error)
+    }
+}
+
+// Reads one command, either a RESP array of bulk strings or an inline line
+// as typed into telnet.
+async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        let Some(count) = line.strip_prefix('*') else {
+            let args: Vec<Vec<u8>> = line.split_whitespace().map(|arg| arg.into()).collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Some(args);
+        };
+        let count: usize = count.parse().ok()?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut header = String::new();
+            reader.read_line(&mut header).await.ok()?;
+            let len: usize = header.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Some(args);
+        }
+    }
+}
+
+async fn serve(server: Server, stream: TcpStream) {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+
+    // Replies and subscribed messages share the socket.
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if write.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let send = |reply: Reply| {
+        let mut frame = Vec::new();
+        reply.encode(&mut frame);
+        let _ = tx.send(frame);
+    };
+
+    let subscribed: Arc<Mutex<HashSet<Vec<u8>>>> = Arc::default();
+    let mut forwarding = false;
+    let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+
+    while let Some(args) = read_command(&mut reader).await {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        match (name.as_str(), queued.as_mut()) {
+            ("MULTI", None) => {
+                queued = Some(Vec::new());
+                send(Reply::Status("OK"));
+            }
+            ("EXEC", Some(_)) => {
+                let commands = queued.take().unwrap_or_default();
+                let mut data = server.data.lock().unwrap();
+                let replies = commands
+                    .iter()
+                    .map(|command| server.execute(&mut data, command))
+                    .collect();
+                send(Reply::Array(replies));
+            }
+            ("DISCARD", Some(_)) => {
+                queued = None;
+                send(Reply::Status("OK"));
+            }
+            ("MULTI", Some(_)) | ("EXEC" | "DISCARD", None) => {
+                send(Reply::Error(format!("{name} not allowed here")));
+            }
+            (_, Some(commands)) => {
+                commands.push(args);
+                send(Reply::Status("QUEUED"));
+            }
+            ("SUBSCRIBE", None) => {
+                for channel in &args[1..] {
+                    let count = {
+                        let mut subscribed = subscribed.lock().unwrap();
+                        subscribed.insert(channel.clone());
+                        subscribed.len()
+                    };
+                    send(Reply::Array(vec![
+                        Reply::bulk(b"subscribe"),
+                        Reply::bulk(channel),
+                        Reply::Integer(count as i64),
+                    ]));
+                }
+                if !forwarding {
+                    forwarding = true;
+                    let mut messages = server.messages.subscribe();
+                    let subscribed = Arc::clone(&subscribed);
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        while let Ok((channel, message)) = messages.recv().await {
+                            if !subscribed.lock().unwrap().contains(&channel) {
+                                continue;
+                            }
+                            let mut frame = Vec::new();
+                            Reply::Array(vec![
+                                Reply::bulk(b"message"),
+                                Reply::bulk(&channel),
+                                Reply::Bulk(Some(message)),
+                            ])
+                            .encode(&mut frame);
+                            if tx.send(frame).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            }
+            ("QUIT", None) => {
+                send(Reply::Status("OK"));
+                break;
+            }
+            (_, None) => {
+                let reply = server.execute(&mut server.data.lock().unwrap(), &args);
+                send(reply);
+            }
+        }
+    }
+}
+
+/// Starts a stand-in on a free port, returning its URL.
+pub async fn start() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let url = format!("redis://{}", listener.local_addr().expect("address"));
+
+    let server = Server {
+        data: Arc::default(),
+        messages: broadcast::channel(1024).0,
+    };
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(serve(server.clone(), stream));
+        }
+    });
+    url
+}
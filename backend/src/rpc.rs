@@ -0,0 +1,228 @@
+use crate::{
+    admin::is_admin_token,
+    error::AppError,
+    poll::{BallotMode, Poll},
+    polls::{cast, create, find, shown, watch},
+    session::SessionEntry,
+    state::AppState,
+    throttle::client_ip,
+};
+use axum::{extract::ConnectInfo, Router};
+use futures_util::{Stream, StreamExt};
+use proto::{
+    poll_service_server::{PollService, PollServiceServer},
+    CastVoteRequest, CastVoteResponse, CreatePollRequest, CreatePollResponse, GetTallyRequest,
+    StreamTallyRequest, Tally,
+};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+};
+use tonic::{service::Routes, Request, Response, Status};
+use tracing::{debug, error, warn};
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("voting.v1");
+}
+
+impl From<AppError> for Status {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::Unauthorized => {
+                warn!("Rejected gRPC call without a valid token");
+                Status::unauthenticated("Unauthorized")
+            }
+            AppError::BadRequest(message) => Status::invalid_argument(message),
+            AppError::Conflict(message) => Status::failed_precondition(message),
+            AppError::Forbidden(message) => Status::permission_denied(message),
+            AppError::NotFound(message) => Status::not_found(message),
+            AppError::NotImplemented(message) => Status::unimplemented(message),
+            AppError::TooManyRequests(message) => Status::resource_exhausted(message),
+            AppError::ServiceUnavailable(message) => Status::unavailable(message),
+            _ => {
+                error!("Server error: {}", e);
+                Status::internal("Internal server error")
+            }
+        }
+    }
+}
+
+/// The `voting.v1.PollService` gRPC service, on the same state as the HTTP
+/// API.
+pub struct Rpc {
+    state: Arc<AppState>,
+}
+
+/// Routes for the gRPC service, to be merged into the app. Clients speak
+/// HTTP/2 without TLS.
+pub fn router(state: &Arc<AppState>) -> Router {
+    Routes::new(PollServiceServer::new(Rpc {
+        state: Arc::clone(state),
+    }))
+    .into_axum_router()
+}
+
+impl Rpc {
+    // Like `Option<Admin>`: calls without a token are public, a wrong token
+    // is refused.
+    fn admin<T>(&self, request: &Request<T>) -> Result<bool, Status> {
+        let Some(value) = request.metadata().get("authorization") else {
+            return Ok(false);
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if is_admin_token(&self.state, token) => Ok(true),
+            _ => Err(AppError::Unauthorized.into()),
+        }
+    }
+}
+
+// Calls are served by the same listener as HTTP, which records the peer.
+fn address<T>(request: &Request<T>) -> IpAddr {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    client_ip(peer, &request.metadata().clone().into_headers())
+}
+
+// Enums go over the wire by the names they have in JSON and the environment.
+fn name(value: impl Serialize) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn parse<T: FromStr>(value: Option<String>, field: &str) -> Result<Option<T>, Status> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Status::invalid_argument(format!("unknown {field} {value}")))
+        })
+        .transpose()
+}
+
+fn tally(state: &AppState, index: usize, poll: &Poll, privileged: bool) -> Tally {
+    let shown = shown(poll, privileged);
+    Tally {
+        poll: index as u32,
+        current: index == state.session.index(),
+        title: poll.title.clone(),
+        ballot: name(poll.ballot),
+        status: name(poll.status()),
+        locked: poll.locked.is_on(),
+        hidden: !poll.results_visible(),
+        red: shown.red as u64,
+        green: shown.green as u64,
+        blue: shown.blue as u64,
+        purple: shown.purple as u64,
+        total: shown.total as u64,
+        ballots: shown.ballots as u64,
+        averages: if poll.ballot == BallotMode::Score {
+            shown
+                .averages()
+                .into_iter()
+                .map(|(color, average)| (color.to_string(), average))
+                .collect()
+        } else {
+            Default::default()
+        },
+    }
+}
+
+#[tonic::async_trait]
+impl PollService for Rpc {
+    async fn create_poll(
+        &self,
+        request: Request<CreatePollRequest>,
+    ) -> Result<Response<CreatePollResponse>, Status> {
+        if !self.admin(&request)? {
+            return Err(AppError::Unauthorized.into());
+        }
+        let request = request.into_inner();
+        let entry = SessionEntry {
+            title: request.title,
+            ballot: parse(request.ballot, "ballot")?,
+            results: parse(request.results, "results")?,
+            privacy: parse(request.privacy, "privacy")?,
+            max_choices: request.max_choices.map(|limit| limit as usize),
+            max_score: request.max_score.map(|limit| limit as usize),
+            max_text_bytes: request.max_text_bytes.map(|limit| limit as usize),
+            correct: request.correct,
+            seconds: request.seconds,
+            opens_at: request.opens_at,
+            closes_at: request.closes_at,
+        };
+        let index = create(&self.state, entry)?;
+        Ok(Response::new(CreatePollResponse { poll: index as u32 }))
+    }
+
+    async fn cast_vote(
+        &self,
+        request: Request<CastVoteRequest>,
+    ) -> Result<Response<CastVoteResponse>, Status> {
+        let privileged = self.admin(&request)?;
+        let address = address(&request);
+        let request = request.into_inner();
+        let index = request.poll as usize;
+        let (voter, replies) = cast(
+            &self.state,
+            index,
+            &request.message,
+            request.voter,
+            request.name,
+            privileged,
+            address,
+        )
+        .await?;
+        let poll = find(&self.state, index)?;
+
+        Ok(Response::new(CastVoteResponse {
+            voter: voter.to_string(),
+            replies: replies.iter().map(ToString::to_string).collect(),
+            tally: Some(tally(&self.state, index, poll, privileged)),
+        }))
+    }
+
+    async fn get_tally(
+        &self,
+        request: Request<GetTallyRequest>,
+    ) -> Result<Response<Tally>, Status> {
+        let privileged = self.admin(&request)?;
+        let index = request.into_inner().poll as usize;
+        debug!("Poll {} requested over gRPC", index);
+        let poll = find(&self.state, index)?;
+        Ok(Response::new(tally(&self.state, index, poll, privileged)))
+    }
+
+    type StreamTallyStream = Pin<Box<dyn Stream<Item = Result<Tally, Status>> + Send>>;
+
+    async fn stream_tally(
+        &self,
+        request: Request<StreamTallyRequest>,
+    ) -> Result<Response<Self::StreamTallyStream>, Status> {
+        let privileged = self.admin(&request)?;
+        let requested = request.into_inner().poll.map(|index| index as usize);
+        if let Some(index) = requested {
+            find(&self.state, index)?;
+        }
+        debug!("Tally stream started for {:?}", requested);
+
+        let tallies = watch(
+            &self.state,
+            requested,
+            privileged,
+            move |state, index, poll| tally(state, index, poll, privileged),
+        );
+        Ok(Response::new(Box::pin(tallies.map(Ok))))
+    }
+}
@@ -1,7 +1,16 @@
-use crate::{error::AppError, state::AppState};
+use crate::{
+    answers::{Answer, Moderation},
+    ballots::{SavedBallot, SavedRanking},
+    counters::Snapshot,
+    crdt::PnCounter,
+    error::AppError,
+    poll::{unix_now_millis, Poll, PollDefinition},
+    quiz::SavedStanding,
+    state::AppState,
+    webhooks::Subscription,
+};
 use axum::extract::State;
-use serde::Deserialize;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
 use std::{
     fs,
     sync::{
@@ -13,14 +22,88 @@ use std::{io::Write, path::Path};
 use tempfile::NamedTempFile;
 use tracing::{error, info, warn};
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct SavedState {
     total_users: usize,
-    red: usize,
-    green: usize,
-    blue: usize,
-    purple: usize,
-    total: usize,
+    // Unix milliseconds. Ballots replicas cast before then are in the saved
+    // counts already.
+    #[serde(default)]
+    saved_at: u64,
+    // Index of the poll the session was on.
+    #[serde(default)]
+    current: usize,
+    // The first poll is kept at the top level, where single-poll saves have
+    // always had it; any further polls of the session follow in `polls`.
+    #[serde(flatten)]
+    first: SavedPoll,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    polls: Vec<SavedPoll>,
+    // Polls added while running, recreated before the saved polls are
+    // restored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    created: Vec<PollDefinition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    quiz: Vec<SavedStanding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    webhooks: Vec<Subscription>,
+}
+
+// Node entries saved by a version that only kept totals.
+const LEGACY_NODE: &str = "legacy";
+
+#[derive(Serialize, Deserialize)]
+struct SavedPoll {
+    // Totals for reading the file; restores use `nodes` when it is there.
+    #[serde(flatten)]
+    counters: Snapshot,
+    // Every node's counts, so replicas restoring the same file, or merging
+    // with peers that outlived them, count each vote once.
+    #[serde(default, skip_serializing_if = "PnCounter::is_empty")]
+    nodes: PnCounter,
+    // Only attributed polls save their ballots. Anonymous ranked polls
+    // save what the runoff needs in `runoff`; other anonymous polls only
+    // their counts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ledger: Vec<SavedBallot>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    runoff: Vec<SavedRanking>,
+    // Written before ballots were attributed; read only to migrate them.
+    #[serde(default, skip_serializing)]
+    rankings: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    answers: Vec<Answer>,
+}
+
+impl SavedPoll {
+    fn new(poll: &Poll, node: &str) -> Self {
+        Self {
+            counters: poll.counters.snapshot(),
+            nodes: poll.counters.state(node),
+            ledger: poll.ballots.saved(),
+            runoff: poll.ballots.rankings(),
+            rankings: Vec::new(),
+            answers: poll.answers.saved(),
+        }
+    }
+
+    fn restore(mut self, poll: &Poll, node: &str, saved_at: u64) {
+        if self.nodes.is_empty() {
+            self.nodes = self.counters.as_node(LEGACY_NODE);
+        }
+        poll.counters.restore(node, &self.nodes);
+        self.runoff
+            .extend(self.rankings.drain(..).map(|ranking| SavedRanking {
+                ranking,
+                ballots: 1,
+            }));
+        poll.ballots.restore(&self.ledger, &self.runoff, saved_at);
+        poll.answers.restore(&self.answers);
+        for answer in &self.answers {
+            if answer.moderation == Moderation::Approved {
+                poll.wordcloud.add(&answer.text);
+            }
+        }
+    }
 }
 
 pub fn load(file_path: &str, State(state): State<Arc<AppState>>) {
@@ -28,33 +111,24 @@ pub fn load(file_path: &str, State(state): State<Arc<AppState>>) {
         match fs::read_to_string(file_path) {
             Ok(data) => match serde_json::from_str::<SavedState>(&data) {
                 Ok(data_read) => {
-                    state.counters.red.store(data_read.red, Release);
-                    state.counters.green.store(data_read.green, Release);
-                    state.counters.blue.store(data_read.blue, Release);
-                    state.counters.purple.store(data_read.purple, Release);
-                    state.counters.total.store(data_read.total, Release);
+                    for definition in data_read.created {
+                        if let Err(e) = state.session.create(definition, &state.config) {
+                            warn!("Failed to recreate a created poll: {}", e);
+                        }
+                    }
+                    let saved_polls = std::iter::once(data_read.first).chain(data_read.polls);
+                    // A session file that changed since the save keeps the
+                    // polls that still line up.
+                    for (saved, poll) in saved_polls.zip(state.session.polls()) {
+                        saved.restore(poll, &state.cluster.node, data_read.saved_at);
+                    }
+                    if state.session.advance(data_read.current).is_none() {
+                        warn!("Saved poll {} is not in the session", data_read.current);
+                    }
+                    state.quiz.restore(&data_read.quiz);
+                    state.webhooks.restore(&data_read.webhooks);
                     state.total_users.store(data_read.total_users, Release);
 
-                    state
-                        .metrics
-                        .votes
-                        .with_label_values(&["red"])
-                        .inc_by(data_read.red.try_into().unwrap());
-                    state
-                        .metrics
-                        .votes
-                        .with_label_values(&["green"])
-                        .inc_by(data_read.green.try_into().unwrap());
-                    state
-                        .metrics
-                        .votes
-                        .with_label_values(&["blue"])
-                        .inc_by(data_read.blue.try_into().unwrap());
-                    state
-                        .metrics
-                        .votes
-                        .with_label_values(&["purple"])
-                        .inc_by(data_read.purple.try_into().unwrap());
                     state
                         .metrics
                         .total_users
@@ -76,14 +150,21 @@ pub fn load(file_path: &str, State(state): State<Arc<AppState>>) {
 }
 
 pub async fn save(file_path: &str, State(state): State<Arc<AppState>>) -> Result<(), AppError> {
-    let saved_state = json!({
-        "total_users": state.total_users.load(Acquire),
-        "red": state.counters.red.load(Acquire),
-        "green": state.counters.green.load(Acquire),
-        "blue": state.counters.blue.load(Acquire),
-        "purple": state.counters.purple.load(Acquire),
-        "total": state.counters.total.load(Acquire),
-    });
+    let mut polls = state
+        .session
+        .polls()
+        .iter()
+        .map(|poll| SavedPoll::new(poll, &state.cluster.node));
+    let saved_state = SavedState {
+        total_users: state.total_users.load(Acquire),
+        saved_at: unix_now_millis(),
+        current: state.session.index(),
+        first: polls.next().expect("A session has at least one poll"),
+        polls: polls.collect(),
+        created: state.session.created(),
+        quiz: state.quiz.saved(),
+        webhooks: state.webhooks.saved(),
+    };
 
     let json_data = serde_json::to_string_pretty(&saved_state)?;
 
@@ -0,0 +1,321 @@
+use crate::{
+    cluster::Replica,
+    config::{Config, MAX_QUIZ_SECONDS},
+    counters::COLORS,
+    crdt::{Register, Stamp},
+    error::AppError,
+    poll::{BallotMode, Poll, PollDefinition, Privacy, Visibility},
+    store::Store,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    iter::FilterMap,
+    slice,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release},
+        },
+        Mutex, OnceLock,
+    },
+};
+use tracing::info;
+
+// One entry of the session file. Anything left out falls back to the poll
+// settings from the environment, the schedule included.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionEntry {
+    pub title: Option<String>,
+    pub ballot: Option<BallotMode>,
+    pub results: Option<Visibility>,
+    pub privacy: Option<Privacy>,
+    pub max_choices: Option<usize>,
+    pub max_score: Option<usize>,
+    pub max_text_bytes: Option<usize>,
+    pub correct: Option<String>,
+    pub seconds: Option<u64>,
+    // Unix seconds.
+    pub opens_at: Option<u64>,
+    pub closes_at: Option<u64>,
+}
+
+impl SessionEntry {
+    pub fn definition(self, defaults: &PollDefinition) -> Result<PollDefinition, String> {
+        let correct = match self.correct {
+            Some(option) => COLORS
+                .iter()
+                .position(|color| *color == option)
+                .ok_or_else(|| format!("unknown correct option {option}"))?,
+            None => defaults.correct,
+        };
+        let definition = PollDefinition {
+            title: self.title.or_else(|| defaults.title.clone()),
+            ballot: self.ballot.unwrap_or(defaults.ballot),
+            results: self.results.unwrap_or(defaults.results),
+            privacy: self.privacy.unwrap_or(defaults.privacy),
+            max_choices: self.max_choices.unwrap_or(defaults.max_choices),
+            max_score: self.max_score.unwrap_or(defaults.max_score),
+            max_text_bytes: self.max_text_bytes.unwrap_or(defaults.max_text_bytes),
+            correct,
+            seconds: self.seconds.unwrap_or(defaults.seconds),
+            opens_at: self.opens_at.or(defaults.opens_at),
+            closes_at: self.closes_at.or(defaults.closes_at),
+        };
+
+        if definition.max_choices == 0
+            || definition.max_score == 0
+            || definition.max_text_bytes == 0
+            || definition.seconds == 0
+        {
+            return Err("limits must be greater than 0".into());
+        }
+        if definition.seconds > MAX_QUIZ_SECONDS {
+            return Err(format!("seconds must be at most {MAX_QUIZ_SECONDS}"));
+        }
+        if let (Some(opens_at), Some(closes_at)) = (definition.opens_at, definition.closes_at) {
+            if opens_at >= closes_at {
+                return Err("opens_at must be before closes_at".into());
+            }
+        }
+        Ok(definition)
+    }
+}
+
+// The ordered polls the presenter steps through. Without a session file it
+// starts with the single poll described by the environment. Polls can be
+// added at the end but never move or go away, so references to them stay
+// valid.
+pub struct Session {
+    slots: Box<[OnceLock<Poll>]>,
+    len: AtomicUsize,
+    // How many polls the session started with, then the definitions of the
+    // polls added while running, in order, for saves and other replicas.
+    starting: usize,
+    created: Mutex<Vec<PollDefinition>>,
+    current: AtomicUsize,
+    // When the presenter last moved the session, so replicas agree on where
+    // it is.
+    moved: Mutex<Stamp>,
+    replica: Replica,
+    // Hands out the counter store of polls created while running.
+    store: Store,
+}
+
+/// The polls of the session so far.
+#[derive(Clone, Copy)]
+pub struct Polls<'a>(&'a [OnceLock<Poll>]);
+
+impl<'a> Polls<'a> {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a Poll> {
+        self.0.get(index)?.get()
+    }
+
+    pub fn iter(&self) -> <Self as IntoIterator>::IntoIter {
+        self.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for Polls<'a> {
+    type Item = &'a Poll;
+    type IntoIter =
+        FilterMap<slice::Iter<'a, OnceLock<Poll>>, fn(&OnceLock<Poll>) -> Option<&Poll>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter().filter_map(OnceLock::get)
+    }
+}
+
+impl Session {
+    /// A session starting with `polls` and room for `room` more to be
+    /// created while it runs.
+    pub fn new(polls: Vec<Poll>, room: usize, replica: Replica, store: Store) -> Self {
+        assert!(!polls.is_empty(), "A session needs at least one poll");
+        let len = polls.len();
+        let slots = polls
+            .into_iter()
+            .map(OnceLock::from)
+            .chain((0..room).map(|_| OnceLock::new()))
+            .collect();
+        Self {
+            slots,
+            len: AtomicUsize::new(len),
+            starting: len,
+            created: Mutex::default(),
+            current: AtomicUsize::new(0),
+            moved: Mutex::default(),
+            replica,
+            store,
+        }
+    }
+
+    pub fn load(config: &Config, store: Store) -> Result<Self, AppError> {
+        let defaults = config.poll_definition();
+        let definitions = match &config.session_path {
+            Some(path) => {
+                let entries: Vec<SessionEntry> = serde_json::from_str(&fs::read_to_string(path)?)?;
+                if entries.is_empty() {
+                    return Err(AppError::Config("Session file lists no polls".into()));
+                }
+                entries
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, entry)| {
+                        entry.definition(&defaults).map_err(|reason| {
+                            AppError::Config(format!("Invalid session poll {i}: {reason}"))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            None => vec![defaults],
+        };
+        info!("Session has {} poll(s)", definitions.len());
+
+        let replica = Replica::new(config);
+        Ok(Self::new(
+            definitions
+                .into_iter()
+                .enumerate()
+                .map(|(index, definition)| poll(index, definition, config, &replica, &store))
+                .collect(),
+            config.created_polls,
+            replica,
+            store,
+        ))
+    }
+
+    pub fn index(&self) -> usize {
+        self.current.load(Relaxed)
+    }
+
+    /// The poll the audience is currently following.
+    pub fn current(&self) -> &Poll {
+        self.polls()
+            .get(self.index())
+            .expect("The current poll is in the session")
+    }
+
+    pub fn replica(&self) -> &Replica {
+        &self.replica
+    }
+
+    pub fn polls(&self) -> Polls<'_> {
+        Polls(&self.slots[..self.len.load(Acquire)])
+    }
+
+    /// Adds a poll at the end of the session and returns its index.
+    pub fn create(&self, definition: PollDefinition, config: &Config) -> Result<usize, AppError> {
+        self.push(None, definition, config)
+    }
+
+    /// Adds a poll another replica created as its poll `index`. Fails if this
+    /// replica's session has a different length, e.g. because both created
+    /// a poll at once.
+    pub fn replicate(
+        &self,
+        index: usize,
+        definition: PollDefinition,
+        config: &Config,
+    ) -> Result<usize, AppError> {
+        self.push(Some(index), definition, config)
+    }
+
+    fn push(
+        &self,
+        expected: Option<usize>,
+        definition: PollDefinition,
+        config: &Config,
+    ) -> Result<usize, AppError> {
+        let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+        let index = self.len.load(Relaxed);
+        if let Some(expected) = expected.filter(|&expected| expected != index) {
+            return Err(AppError::Conflict(format!(
+                "Expected poll {expected} to be added, but the session has {index} polls"
+            )));
+        }
+        let slot = self
+            .slots
+            .get(index)
+            .ok_or_else(|| AppError::Conflict("Session has no room for more polls".into()))?;
+        let _ = slot.set(poll(
+            index,
+            definition.clone(),
+            config,
+            &self.replica,
+            &self.store,
+        ));
+        created.push(definition);
+        // Published only once the poll is in place.
+        self.len.store(index + 1, Release);
+        Ok(index)
+    }
+
+    /// The polls added while running, for recreating them after a restart.
+    pub fn created(&self) -> Vec<PollDefinition> {
+        self.created
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Index of the first poll added while running.
+    pub fn created_from(&self) -> usize {
+        self.starting
+    }
+
+    /// Moves everyone to the poll at `index`. `None` if there is no such poll.
+    pub fn advance(&self, index: usize) -> Option<&Poll> {
+        let poll = self.polls().get(index)?;
+        self.current.store(index, Relaxed);
+        Some(poll)
+    }
+
+    /// Moves everyone to the poll at `index` on the presenter's say, stamped
+    /// so the move carries over to the other replicas.
+    pub fn steer(&self, index: usize) -> Option<&Poll> {
+        let mut moved = self.moved.lock().unwrap_or_else(|e| e.into_inner());
+        let poll = self.advance(index)?;
+        *moved = Stamp::after(Some(&moved), &self.replica.name);
+        Some(poll)
+    }
+
+    pub fn shared(&self) -> Register<usize> {
+        let moved = self.moved.lock().unwrap_or_else(|e| e.into_inner());
+        Register {
+            value: self.index(),
+            stamp: moved.clone(),
+        }
+    }
+
+    /// Follows a later move made on another replica. Returns whether the
+    /// session moved. A move to a poll not created here yet waits for the
+    /// next full sync.
+    pub fn merge(&self, shared: &Register<usize>) -> bool {
+        let mut moved = self.moved.lock().unwrap_or_else(|e| e.into_inner());
+        if shared.stamp <= *moved || self.polls().get(shared.value).is_none() {
+            return false;
+        }
+        *moved = shared.stamp.clone();
+        self.current.swap(shared.value, Relaxed) != shared.value
+    }
+}
+
+fn poll(
+    index: usize,
+    definition: PollDefinition,
+    config: &Config,
+    replica: &Replica,
+    store: &Store,
+) -> Poll {
+    Poll::new(
+        definition,
+        config.wordcloud_top_k,
+        replica,
+        store.counters(index, &replica.node),
+    )
+}
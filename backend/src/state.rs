@@ -1,31 +1,28 @@
-use crate::metrics::Metrics;
+use crate::{
+    cluster::Cluster, config::Config, events::Events, leader::Leader, metrics::Metrics, quiz::Quiz,
+    session::Session, throttle::Throttle, webhooks::Webhooks,
+};
+use axum::extract::ws::Message;
 use std::sync::atomic::AtomicUsize;
 use tokio::sync::broadcast::Sender;
 
 pub struct AppState {
-    pub counters: Counters,
+    pub config: Config,
+    pub session: Session,
+    pub quiz: Quiz,
     pub concurrent_users: AtomicUsize,
     pub total_users: AtomicUsize,
-    pub broadcast_tx: Sender<String>,
+    // Frames are serialized once and shared; cloning a text `Message` only
+    // bumps a reference count. Only this replica's sockets listen; the
+    // others build their own frames from gossip.
+    pub broadcast_tx: Sender<Message>,
+    // Presenter connections listen here instead, and also get what the
+    // audience is not shown.
+    pub presenter_tx: Sender<Message>,
     pub metrics: Metrics,
-}
-
-pub struct Counters {
-    pub red: AtomicUsize,
-    pub green: AtomicUsize,
-    pub blue: AtomicUsize,
-    pub purple: AtomicUsize,
-    pub total: AtomicUsize,
-}
-
-impl Default for Counters {
-    fn default() -> Self {
-        Self {
-            red: AtomicUsize::new(0),
-            green: AtomicUsize::new(0),
-            blue: AtomicUsize::new(0),
-            purple: AtomicUsize::new(0),
-            total: AtomicUsize::new(0),
-        }
-    }
+    pub cluster: Cluster,
+    pub leader: Leader,
+    pub events: Events,
+    pub webhooks: Webhooks,
+    pub throttle: Throttle,
 }
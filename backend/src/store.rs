@@ -0,0 +1,573 @@
+use crate::{
+    counters::{Counters, Snapshot, COLORS},
+    crdt::{GCounter, PnCounter},
+    error::AppError,
+};
+use futures_util::future::BoxFuture;
+use redis::{aio::MultiplexedConnection, Client};
+use std::{
+    array,
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, time::timeout};
+use tracing::{debug, error, info};
+
+// Hash fields for the option tallies followed by the ballot count, in the
+// order `Counters` keeps them.
+const FIELDS: [&str; 5] = ["red", "green", "blue", "purple", "ballots"];
+const BALLOTS: usize = COLORS.len();
+
+// How often tallies are read back from Redis when this replica takes no
+// ballots, which bounds how far behind other replicas' ballots run.
+// Writes that failed are retried then too.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    Atomic,
+    Redis,
+}
+
+impl FromStr for StoreKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "atomic" => Ok(Self::Atomic),
+            "redis" => Ok(Self::Redis),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a poll's counts are kept: ballots are recorded into it and
+/// tallies served from it. Besides the tallies it keeps the counts' CRDT
+/// state, which saves and gossip carry.
+pub trait CounterStore: Send + Sync {
+    /// Swaps a ballot cast here for another in a single write, so the
+    /// change is observed as one delta. Either side may be missing.
+    fn replace(&self, old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>);
+
+    /// Records one ballot adding `amount` to each option index.
+    fn record(&self, tallies: &[(usize, usize)]) {
+        self.replace(None, Some(tallies));
+    }
+
+    /// Takes back a ballot previously passed to `record`.
+    fn retract(&self, tallies: &[(usize, usize)]) {
+        self.replace(Some(tallies), None);
+    }
+
+    /// Like `replace`, for a ballot another replica took and shared through
+    /// its ledger. A store the replicas share has it already.
+    fn replicate(&self, old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>) {
+        self.replace(old, new);
+    }
+
+    fn snapshot(&self) -> Snapshot;
+
+    /// The options whose tallies changed since the last call, in display
+    /// order.
+    fn take_changed(&self) -> Vec<&'static str>;
+
+    /// The full CRDT state: this node's votes under `node` along with every
+    /// other node's entry known here.
+    fn state(&self, node: &str) -> PnCounter;
+
+    /// Merges another node's state. Returns whether the tallies changed.
+    fn merge(&self, node: &str, other: &PnCounter) -> bool;
+
+    /// Starts over from a saved state.
+    fn restore(&self, node: &str, saved: &PnCounter);
+
+    /// Settles what the store held before this process started with what it
+    /// restored from the save file.
+    fn reconcile(&self, _node: &str) -> BoxFuture<'_, Result<(), AppError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The default: counts live only in the process' atomics and the state
+/// file, and replicas share them by gossip.
+impl CounterStore for Counters {
+    fn replace(&self, old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>) {
+        Counters::replace(self, old, new);
+    }
+
+    fn record(&self, tallies: &[(usize, usize)]) {
+        Counters::record(self, tallies);
+    }
+
+    fn retract(&self, tallies: &[(usize, usize)]) {
+        Counters::retract(self, tallies);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Counters::snapshot(self)
+    }
+
+    fn take_changed(&self) -> Vec<&'static str> {
+        Counters::take_changed(self)
+    }
+
+    fn state(&self, node: &str) -> PnCounter {
+        Counters::state(self, node)
+    }
+
+    fn merge(&self, node: &str, other: &PnCounter) -> bool {
+        Counters::merge(self, node, other)
+    }
+
+    fn restore(&self, node: &str, saved: &PnCounter) {
+        Counters::restore(self, node, saved);
+    }
+}
+
+/// Hands every poll its counter store.
+#[derive(Clone)]
+pub enum Store {
+    Atomic,
+    Redis(Client, MultiplexedConnection),
+}
+
+impl Store {
+    pub async fn connect(kind: StoreKind, url: &str) -> Result<Self, AppError> {
+        match kind {
+            StoreKind::Atomic => Ok(Self::Atomic),
+            StoreKind::Redis => {
+                let client = Client::open(url)?;
+                let connection = client.get_multiplexed_async_connection().await?;
+                info!("Connected to Redis counter store");
+                Ok(Self::Redis(client, connection))
+            }
+        }
+    }
+
+    /// The store for poll `index`, counting this run's ballots as `node`.
+    pub fn counters(&self, index: usize, node: &str) -> Box<dyn CounterStore> {
+        match self {
+            Self::Atomic => Box::new(Counters::default()),
+            Self::Redis(client, connection) => {
+                Box::new(RedisCounters::new(index, node, client, connection))
+            }
+        }
+    }
+}
+
+// The option slots a ballot touches, as a bit set.
+fn touched(old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>) -> usize {
+    old.into_iter()
+        .chain(new)
+        .flatten()
+        .fold(0, |changed, &(index, _)| changed | 1 << index)
+}
+
+struct Shared {
+    nodes: String,
+    node: String,
+    client: Client,
+    // Replaced when it fails; a multiplexed connection never reconnects.
+    connection: Mutex<MultiplexedConnection>,
+    // Every other node's net counts as last read from Redis.
+    others: Mutex<[i64; FIELDS.len()]>,
+    // Whether this node's share changed since Redis last took it.
+    unwritten: AtomicBool,
+    wake: Notify,
+    changed: AtomicUsize,
+    // This node's own share and what saves and gossip brought in.
+    local: Counters,
+}
+
+/// Counts kept in Redis, where every replica using it writes its ballots
+/// and reads the tallies. `poll:{index}:nodes` holds every node's share as
+/// `{node}:added:{field}` and `{node}:retracted:{field}`, and the tallies
+/// are their sum. Each node only ever sets its own fields to its running
+/// totals, so a write that is retried or repeated counts nothing twice, and
+/// a replica restoring a save adds only what Redis is missing. Ballots cast
+/// while a write is under way go together in the next one; the tallies
+/// always take this node's share from memory, so a voter sees their own
+/// ballot before Redis has it.
+pub struct RedisCounters {
+    shared: Arc<Shared>,
+}
+
+impl RedisCounters {
+    pub fn new(
+        index: usize,
+        node: &str,
+        client: &Client,
+        connection: &MultiplexedConnection,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            nodes: format!("poll:{index}:nodes"),
+            node: node.to_string(),
+            client: client.clone(),
+            connection: Mutex::new(connection.clone()),
+            others: Mutex::default(),
+            unwritten: AtomicBool::new(false),
+            wake: Notify::new(),
+            changed: AtomicUsize::new(0),
+            local: Counters::default(),
+        });
+        tokio::spawn(write_behind(Arc::clone(&shared)));
+        Self { shared }
+    }
+}
+
+// The writer outlives the store only long enough to write what is left.
+impl Drop for RedisCounters {
+    fn drop(&mut self) {
+        self.shared.wake.notify_one();
+    }
+}
+
+// Writes this node's share when it changed, and reads everyone's. One
+// request at a time, so reads never go back in time. Once the store is
+// dropped, makes one last attempt and stops.
+async fn write_behind(shared: Arc<Shared>) {
+    loop {
+        let _ = timeout(REFRESH_INTERVAL, shared.wake.notified()).await;
+        let last = Arc::strong_count(&shared) == 1;
+        // Taken before the share is read, so a ballot cast meanwhile is
+        // written next time round.
+        let unwritten = shared.unwritten.swap(false, Relaxed);
+        let share = unwritten.then(|| shared.share());
+        match sync(&shared, share.as_deref()).await {
+            Ok(fields) => shared.read(&fields),
+            Err(e) => {
+                if unwritten {
+                    shared.unwritten.store(true, Relaxed);
+                    error!("Failed to write counts to Redis, retrying: {}", e);
+                } else {
+                    debug!("Failed to read counts from Redis: {}", e);
+                }
+                if !last {
+                    match shared.client.get_multiplexed_async_connection().await {
+                        Ok(connection) => {
+                            *shared.connection.lock().unwrap_or_else(|e| e.into_inner()) =
+                                connection;
+                        }
+                        Err(e) => debug!("Failed to reconnect to Redis: {}", e),
+                    }
+                }
+            }
+        }
+        if last {
+            if shared.unwritten.load(Relaxed) {
+                error!("Dropping counts of {} Redis never took", shared.nodes);
+            }
+            return;
+        }
+    }
+}
+
+// Sets `share`, if any, and reads back every node's.
+async fn sync(
+    shared: &Shared,
+    share: Option<&[(String, u64)]>,
+) -> redis::RedisResult<HashMap<String, u64>> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(share) = share.filter(|share| !share.is_empty()) {
+        pipe.cmd("HSET").arg(&shared.nodes).arg(share).ignore();
+    }
+    pipe.hgetall(&shared.nodes);
+    let (fields,): (HashMap<String, u64>,) = pipe.query_async(&mut shared.connection()).await?;
+    Ok(fields)
+}
+
+// `node`'s entries of `counter` as Redis fields.
+fn node_fields(counter: &PnCounter, node: &str) -> Vec<(String, u64)> {
+    let mut fields = Vec::new();
+    for (kind, counts) in [
+        ("added", counter.added.get(node)),
+        ("retracted", counter.retracted.get(node)),
+    ] {
+        for (field, &count) in FIELDS.iter().zip(counts) {
+            fields.push((format!("{node}:{kind}:{field}"), count));
+        }
+    }
+    fields
+}
+
+impl Shared {
+    fn connection(&self) -> MultiplexedConnection {
+        self.connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn share(&self) -> Vec<(String, u64)> {
+        node_fields(&self.local.state(&self.node), &self.node)
+    }
+
+    // Takes in every node's share as read from Redis.
+    fn read(&self, fields: &HashMap<String, u64>) {
+        let others = parse_nodes(fields).without(&self.node);
+        let (added, retracted) = (
+            others.added.value(FIELDS.len()),
+            others.retracted.value(FIELDS.len()),
+        );
+        let read: [i64; FIELDS.len()] =
+            array::from_fn(|slot| added[slot] as i64 - retracted[slot] as i64);
+        let mut others = self.others.lock().unwrap_or_else(|e| e.into_inner());
+        let changed = (0..COLORS.len())
+            .filter(|&slot| read[slot] != others[slot])
+            .fold(0, |changed, slot| changed | 1 << slot);
+        *others = read;
+        self.changed.fetch_or(changed, Relaxed);
+    }
+}
+
+impl CounterStore for RedisCounters {
+    fn replace(&self, old: Option<&[(usize, usize)]>, new: Option<&[(usize, usize)]>) {
+        self.shared.local.replace(old, new);
+        self.shared.unwritten.store(true, Relaxed);
+        self.shared.changed.fetch_or(touched(old, new), Relaxed);
+        self.shared.wake.notify_one();
+    }
+
+    // Whoever took the ballot wrote it to Redis. Replicas changing the same
+    // voter's ballot at once may both take back the ballot it replaced; the
+    // ledgers still agree on which one stands.
+    fn replicate(&self, _: Option<&[(usize, usize)]>, _: Option<&[(usize, usize)]>) {}
+
+    fn snapshot(&self) -> Snapshot {
+        let shared = &self.shared;
+        let own = shared.local.state(&shared.node);
+        let (added, retracted) = (own.added.get(&shared.node), own.retracted.get(&shared.node));
+        let others = *shared.others.lock().unwrap_or_else(|e| e.into_inner());
+        let count = |slot: usize| {
+            let own = |counts: &[u64]| counts.get(slot).copied().unwrap_or_default() as i64;
+            let count = others[slot] + own(added) - own(retracted);
+            count.max(0) as usize
+        };
+        Snapshot {
+            red: count(0),
+            green: count(1),
+            blue: count(2),
+            purple: count(3),
+            total: (0..COLORS.len()).map(count).sum(),
+            ballots: count(BALLOTS),
+        }
+    }
+
+    fn take_changed(&self) -> Vec<&'static str> {
+        let changed = self.shared.changed.swap(0, Relaxed);
+        COLORS
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| changed & 1 << index != 0)
+            .map(|(_, color)| color)
+            .collect()
+    }
+
+    fn state(&self, node: &str) -> PnCounter {
+        self.shared.local.state(node)
+    }
+
+    // Other replicas' ballots reach the tallies through Redis.
+    fn merge(&self, node: &str, other: &PnCounter) -> bool {
+        self.shared.local.merge(node, other);
+        false
+    }
+
+    fn restore(&self, node: &str, saved: &PnCounter) {
+        self.shared.local.restore(node, saved);
+    }
+
+    // Redis may have lost counts the save file kept, e.g. after a flush, and
+    // the file may predate ballots Redis took since. Each node's share ends
+    // up as the larger of the two, in Redis and in the next save.
+    fn reconcile(&self, node: &str) -> BoxFuture<'_, Result<(), AppError>> {
+        let node = node.to_string();
+        Box::pin(async move {
+            let shared = &self.shared;
+            let mut connection = shared.connection();
+            let fields: HashMap<String, u64> = redis::cmd("HGETALL")
+                .arg(&shared.nodes)
+                .query_async(&mut connection)
+                .await?;
+            let stored = parse_nodes(&fields);
+            let restored = shared.local.state(&node);
+            let mut missing: Vec<(String, u64)> = restored
+                .added
+                .nodes()
+                .chain(restored.retracted.nodes())
+                .map(|(name, _)| name)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .flat_map(|name| node_fields(&restored, name))
+                .collect();
+            missing.retain(|(field, count)| fields.get(field).is_none_or(|held| count > held));
+            shared.local.merge(&node, &stored);
+
+            let fields = sync(shared, Some(&missing)).await?;
+            shared.read(&fields);
+            Ok(())
+        })
+    }
+}
+
+fn parse_nodes(fields: &HashMap<String, u64>) -> PnCounter {
+    let mut entries: HashMap<(&str, &str), [u64; FIELDS.len()]> = HashMap::new();
+    for (key, &count) in fields {
+        // Node names may contain colons; the kind and field never do.
+        let mut parts = key.rsplitn(3, ':');
+        let (Some(field), Some(kind), Some(node)) = (parts.next(), parts.next(), parts.next())
+        else {
+            debug!("Ignoring Redis field {}", key);
+            continue;
+        };
+        let Some(slot) = FIELDS.iter().position(|name| *name == field) else {
+            debug!("Ignoring Redis field {}", key);
+            continue;
+        };
+        entries.entry((node, kind)).or_default()[slot] = count;
+    }
+
+    let mut counter = PnCounter::default();
+    for ((node, kind), counts) in entries {
+        let counter: &mut GCounter = match kind {
+            "added" => &mut counter.added,
+            "retracted" => &mut counter.retracted,
+            _ => continue,
+        };
+        counter.set(node, &counts);
+    }
+    counter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_standin;
+    use tokio::time::sleep;
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    async fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+        let waited = timeout(PATIENCE, async {
+            while !done() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_ok(), "Timed out waiting until {what}");
+    }
+
+    fn counts(snapshot: Snapshot) -> [usize; 5] {
+        [
+            snapshot.red,
+            snapshot.green,
+            snapshot.blue,
+            snapshot.purple,
+            snapshot.ballots,
+        ]
+    }
+
+    #[tokio::test]
+    async fn replicas_serve_tallies_from_redis() {
+        let url = redis_standin::start().await;
+        let a = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        let b = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        let a = a.counters(0, "a/1");
+        let b = b.counters(0, "b/1");
+
+        a.record(&[(0, 1)]);
+        // A voter sees their ballot before Redis has it.
+        assert_eq!(counts(a.snapshot()), [1, 0, 0, 0, 1]);
+        b.record(&[(2, 2)]);
+        a.replace(Some(&[(0, 1)]), Some(&[(1, 1)]));
+        // Ledgers share this one, but the replica that took it wrote it.
+        b.replicate(Some(&[(0, 1)]), Some(&[(1, 1)]));
+
+        let expected = [0, 1, 2, 0, 2];
+        eventually("both replicas read the same tallies", || {
+            counts(a.snapshot()) == expected && counts(b.snapshot()) == expected
+        })
+        .await;
+        let changed = b.take_changed();
+        assert!(changed.contains(&"green") && changed.contains(&"blue"));
+        assert!(b.take_changed().is_empty());
+    }
+
+    async fn redis_counters(url: &str, node: &str) -> RedisCounters {
+        let Store::Redis(client, connection) = Store::connect(StoreKind::Redis, url).await.unwrap()
+        else {
+            unreachable!("connected to Redis");
+        };
+        RedisCounters::new(0, node, &client, &connection)
+    }
+
+    #[tokio::test]
+    async fn rewriting_a_share_counts_it_once() {
+        let url = redis_standin::start().await;
+        let a = redis_counters(&url, "a/1").await;
+        let b = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        let b = b.counters(0, "b/1");
+
+        a.record(&[(0, 1)]);
+        eventually("the other replica reads the ballot", || {
+            counts(b.snapshot()) == [1, 0, 0, 0, 1]
+        })
+        .await;
+        // As when a write that Redis took is retried after a lost reply.
+        for _ in 0..2 {
+            sync(&a.shared, Some(&a.shared.share())).await.unwrap();
+        }
+        sleep(REFRESH_INTERVAL * 3).await;
+        assert_eq!(counts(b.snapshot()), [1, 0, 0, 0, 1]);
+        assert_eq!(counts(a.snapshot()), [1, 0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn dropped_stores_write_what_is_left_and_stop() {
+        let url = redis_standin::start().await;
+        let a = redis_counters(&url, "a/1").await;
+        let writer = Arc::downgrade(&a.shared);
+        a.record(&[(1, 1)]);
+        drop(a);
+
+        eventually("the writer stops", || writer.strong_count() == 0).await;
+        let b = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        let b = b.counters(0, "b/1");
+        eventually("the last ballot is read", || {
+            counts(b.snapshot()) == [0, 1, 0, 0, 1]
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reconciling_adds_what_redis_is_missing_once() {
+        let url = redis_standin::start().await;
+        let before = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        let before = before.counters(0, "a/1");
+        before.record(&[(0, 1)]);
+        before.record(&[(0, 1)]);
+        eventually("the ballots are written", || {
+            counts(before.snapshot()) == [2, 0, 0, 0, 2] && before.take_changed() == ["red"]
+        })
+        .await;
+
+        // The save has both ballots along with some Redis lost since.
+        let mut saved = before.state("a/1");
+        saved.set("lost/1", &[3, 0, 0, 0, 3], &[1, 0, 0, 0, 1]);
+
+        let store = Store::connect(StoreKind::Redis, &url).await.unwrap();
+        for _ in 0..2 {
+            let after = store.counters(0, "a/2");
+            after.restore("a/2", &saved);
+            after.reconcile("a/2").await.unwrap();
+            assert_eq!(counts(after.snapshot()), [4, 0, 0, 0, 4]);
+            assert_eq!(after.state("a/2"), saved);
+        }
+    }
+}
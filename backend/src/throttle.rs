@@ -0,0 +1,213 @@
+use crate::{config::Config, state::AppState};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    hash::Hash,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use tracing::debug;
+
+// Seconds' worth of messages a quiet voter or address can send at once.
+const BURST_SECONDS: f64 = 5.0;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The address a request came from. Behind the proxy every connection comes
+/// from a private address, so for those the last `X-Forwarded-For` hop,
+/// which the proxy appends itself, is taken instead.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientIp(client_ip(peer, &parts.headers)))
+    }
+}
+
+/// Where a request from `peer` with `headers` came from. Clients reaching
+/// the backend directly cannot pass off a forwarded address as their own.
+pub fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap) -> IpAddr {
+    let forwarded = || {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|hop| hop.trim().parse().ok())
+    };
+    match peer {
+        Some(peer) if !internal(peer) => peer,
+        _ => forwarded()
+            .or(peer)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+    }
+}
+
+fn internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local(),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl Bucket {
+    fn full(rate: f64, now: Instant) -> Self {
+        Self {
+            tokens: rate * BURST_SECONDS,
+            filled_at: now,
+        }
+    }
+
+    // Tops the bucket up for the time since it was last filled; returns
+    // whether it is full again.
+    fn refill(&mut self, rate: f64, now: Instant) -> bool {
+        let capacity = rate * BURST_SECONDS;
+        let elapsed = now.duration_since(self.filled_at).as_secs_f64();
+        self.tokens = (self.tokens + rate * elapsed).min(capacity);
+        self.filled_at = now;
+        self.tokens >= capacity
+    }
+}
+
+// Token buckets by key, each refilled at the same rate.
+struct Buckets<K> {
+    rate: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.into(),
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.retain(|_, bucket| !bucket.refill(self.rate, now));
+    }
+}
+
+/// Spaces out the messages of each voter and each client address, whether
+/// they come over a websocket or one of the APIs.
+pub struct Throttle {
+    voters: Buckets<Arc<str>>,
+    addresses: Buckets<IpAddr>,
+}
+
+impl Throttle {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            voters: Buckets::new(config.voter_rate),
+            addresses: Buckets::new(config.address_rate),
+        }
+    }
+
+    /// Whether `voter` may send a message from `address` now. A refused
+    /// message takes nothing from either allowance.
+    pub fn allow(&self, address: IpAddr, voter: &str) -> bool {
+        let now = Instant::now();
+        let mut voters = self
+            .voters
+            .buckets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut addresses = self
+            .addresses
+            .buckets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let by_voter = voters
+            .entry(voter.into())
+            .or_insert_with(|| Bucket::full(self.voters.rate, now));
+        let by_address = addresses
+            .entry(address)
+            .or_insert_with(|| Bucket::full(self.addresses.rate, now));
+        by_voter.refill(self.voters.rate, now);
+        by_address.refill(self.addresses.rate, now);
+        if by_voter.tokens < 1.0 || by_address.tokens < 1.0 {
+            debug!("Throttled voter {} from {}", voter, address);
+            return false;
+        }
+        by_voter.tokens -= 1.0;
+        by_address.tokens -= 1.0;
+        true
+    }
+}
+
+/// Forgets voters and addresses that have been quiet long enough to be owed
+/// a full allowance again.
+pub fn start(state: &Arc<AppState>) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut interval = interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            state.throttle.voters.prune(now);
+            state.throttle.addresses.prune(now);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn voters_and_addresses_have_separate_allowances() {
+        let mut config = Config::test("a");
+        config.voter_rate = 1;
+        config.address_rate = 2;
+        let throttle = Throttle::new(&config);
+        let here = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let there = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8));
+
+        // Five seconds' worth for the voter, then nothing.
+        assert!((0..5).all(|_| throttle.allow(there, "alice")));
+        assert!(!throttle.allow(there, "alice"));
+        // Fresh ids from one address all count against it.
+        assert!((0..10).all(|i| throttle.allow(here, &format!("voter-{i}"))));
+        assert!(!throttle.allow(here, "voter-10"));
+        assert!(throttle.allow(there, "voter-10"));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_taken_from_the_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("192.0.2.1, 198.51.100.2"),
+        );
+        let proxy = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 5));
+        let client = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+        assert_eq!(
+            client_ip(Some(proxy), &headers),
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2))
+        );
+        assert_eq!(client_ip(Some(client), &headers), client);
+        assert_eq!(client_ip(Some(proxy), &HeaderMap::new()), proxy);
+    }
+}
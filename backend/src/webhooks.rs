@@ -0,0 +1,657 @@
+use crate::{
+    admin::Admin, cluster::Gossip, counters::COLORS, error::AppError, poll::unix_now_millis,
+    polls::tallies, state::AppState,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, Request, StatusCode, Uri},
+    Json,
+};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{interval, sleep, timeout};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+// Attempts per delivery before it goes to the dead letters. Retries wait
+// 1, 2, 4 and 8 seconds.
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Oldest dead letters are dropped beyond this many.
+const DEAD_LETTERS: usize = 100;
+
+// How often the current poll's counts are checked for thresholds and leads.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Marks a secret sealed with the webhook key, as opposed to one saved in
+// plain text before secrets were sealed.
+const SEALED: &str = "sealed:";
+const NONCE_BYTES: usize = 12;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    #[serde(rename = "poll.opened")]
+    Opened,
+    #[serde(rename = "poll.closed")]
+    Closed,
+    // The ballot count reached one of the subscription's thresholds.
+    #[serde(rename = "poll.threshold")]
+    Threshold,
+    // An option moved ahead of all the others.
+    #[serde(rename = "poll.lead")]
+    Lead,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Opened => "poll.opened",
+            EventKind::Closed => "poll.closed",
+            EventKind::Threshold => "poll.threshold",
+            EventKind::Lead => "poll.lead",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub url: String,
+    // Every event when empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    #[serde(default)]
+    pub thresholds: Vec<usize>,
+    // Signs every payload; receivers check `X-Webhook-Signature`. Kept
+    // sealed with the webhook key, in saves and gossip too, and only opened
+    // to sign a delivery.
+    pub secret: String,
+}
+
+impl Subscription {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub delivery: String,
+    pub subscription: String,
+    pub url: String,
+    pub event: EventKind,
+    pub payload: Value,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+// Seals and opens subscription secrets.
+struct Vault(ChaCha20Poly1305);
+
+impl Vault {
+    fn new(key: &str) -> Self {
+        Self(ChaCha20Poly1305::new(&Sha256::digest(key.as_bytes())))
+    }
+
+    fn seal(&self, secret: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, secret.as_bytes())
+            .expect("Sealing a secret cannot fail");
+        format!("{SEALED}{}{}", hex::encode(nonce), hex::encode(sealed))
+    }
+
+    fn open(&self, sealed: &str) -> Option<String> {
+        let bytes = hex::decode(sealed.strip_prefix(SEALED)?).ok()?;
+        if bytes.len() < NONCE_BYTES {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_BYTES);
+        let secret = self.0.decrypt(Nonce::from_slice(nonce), sealed).ok()?;
+        String::from_utf8(secret).ok()
+    }
+}
+
+/// Webhook subscriptions and the deliveries that never got through.
+pub struct Webhooks {
+    subscriptions: Mutex<Vec<Subscription>>,
+    // Ids unsubscribed while running, so a replica still sharing one
+    // doesn't bring it back.
+    removed: Mutex<HashSet<String>>,
+    dead: Mutex<VecDeque<DeadLetter>>,
+    // None without a webhook key, which disables subscribing.
+    vault: Option<Vault>,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl Webhooks {
+    pub fn new(key: Option<&str>) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+        Self {
+            subscriptions: Mutex::default(),
+            removed: Mutex::default(),
+            dead: Mutex::default(),
+            vault: key.map(Vault::new),
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        }
+    }
+
+    /// Adds a subscription, or replaces the one with the same id.
+    pub fn subscribe(&self, subscription: Subscription) {
+        if !secure(&subscription.url) {
+            warn!(
+                "Ignoring webhook {}, {} is not an https URL",
+                subscription.id, subscription.url
+            );
+            return;
+        }
+        if self
+            .removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&subscription.id)
+        {
+            return;
+        }
+        let mut subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        subscriptions.retain(|existing| existing.id != subscription.id);
+        subscriptions.push(subscription);
+    }
+
+    pub fn unsubscribe(&self, id: &str) -> bool {
+        self.removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_string());
+        let mut subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        let before = subscriptions.len();
+        subscriptions.retain(|existing| existing.id != id);
+        subscriptions.len() != before
+    }
+
+    pub fn saved(&self) -> Vec<Subscription> {
+        self.subscriptions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn removed(&self) -> Vec<String> {
+        self.removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Takes back the subscriptions of a save, sealing the secrets of older
+    /// saves. Those are dropped without a key to seal them with, as are
+    /// older plain http URLs.
+    pub fn restore(&self, saved: &[Subscription]) {
+        let restored = saved
+            .iter()
+            .filter_map(|subscription| {
+                if !secure(&subscription.url) {
+                    warn!(
+                        "Dropping webhook {}, {} is not an https URL",
+                        subscription.id, subscription.url
+                    );
+                    return None;
+                }
+                if subscription.secret.starts_with(SEALED) {
+                    return Some(subscription.clone());
+                }
+                let Some(vault) = &self.vault else {
+                    warn!(
+                        "Dropping webhook {}, its secret can't be sealed without RUST_WEBHOOK_KEY",
+                        subscription.id
+                    );
+                    return None;
+                };
+                Some(Subscription {
+                    secret: vault.seal(&subscription.secret),
+                    ..subscription.clone()
+                })
+            })
+            .collect();
+        *self.subscriptions.lock().unwrap_or_else(|e| e.into_inner()) = restored;
+    }
+
+    fn open(&self, subscription: &Subscription) -> Option<String> {
+        self.vault.as_ref()?.open(&subscription.secret)
+    }
+
+    fn matching(&self, filter: impl Fn(&Subscription) -> bool) -> Vec<Subscription> {
+        self.subscriptions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|subscription| filter(subscription))
+            .cloned()
+            .collect()
+    }
+
+    fn bury(&self, letter: DeadLetter) -> usize {
+        let mut dead = self.dead.lock().unwrap_or_else(|e| e.into_inner());
+        if dead.len() == DEAD_LETTERS {
+            dead.pop_front();
+        }
+        dead.push_back(letter);
+        dead.len()
+    }
+}
+
+/// Tells the subscribers of `kind` about poll `index`. `detail` is merged
+/// into the payload. Only the leader delivers, so a cluster sends each event
+/// once.
+pub fn notify(state: &Arc<AppState>, kind: EventKind, index: usize, detail: Value) {
+    notify_where(state, kind, index, detail, |_| true);
+}
+
+fn notify_where(
+    state: &Arc<AppState>,
+    kind: EventKind,
+    index: usize,
+    detail: Value,
+    filter: impl Fn(&Subscription) -> bool,
+) {
+    if !state.leader.is_leader() {
+        return;
+    }
+    let Some(poll) = state.session.polls().get(index) else {
+        return;
+    };
+    let subscriptions = state
+        .webhooks
+        .matching(|subscription| subscription.wants(kind) && filter(subscription));
+    if subscriptions.is_empty() {
+        return;
+    }
+
+    let mut payload = json!({
+        "event": kind,
+        "poll": index,
+        "at": unix_now_millis(),
+        "results": tallies(state, index, poll, true),
+    });
+    if let (Some(payload), Value::Object(detail)) = (payload.as_object_mut(), detail) {
+        payload.extend(detail);
+    }
+
+    for subscription in subscriptions {
+        let mut payload = payload.clone();
+        let delivery = Uuid::new_v4().to_string();
+        payload["id"] = json!(delivery);
+        tokio::spawn(deliver(
+            Arc::clone(state),
+            subscription,
+            kind,
+            delivery,
+            payload,
+        ));
+    }
+}
+
+async fn deliver(
+    state: Arc<AppState>,
+    subscription: Subscription,
+    kind: EventKind,
+    delivery: String,
+    payload: Value,
+) {
+    let Some(secret) = state.webhooks.open(&subscription) else {
+        warn!(
+            "Can't open the secret of webhook {}, is RUST_WEBHOOK_KEY the same everywhere?",
+            subscription.id
+        );
+        return;
+    };
+    let body = payload.to_string();
+    let signature = format!("sha256={}", sign(secret.as_bytes(), body.as_bytes()));
+    let metrics = &state.metrics.webhook_deliveries;
+
+    let mut attempt = 1;
+    loop {
+        let error = match post(&state, &subscription, kind, &delivery, &signature, &body).await {
+            Ok(()) => {
+                debug!("Delivered {} to {}", kind.as_str(), subscription.url);
+                metrics.with_label_values(&["delivered"]).inc();
+                return;
+            }
+            Err(error) => error,
+        };
+        metrics.with_label_values(&["failed"]).inc();
+
+        if attempt == MAX_ATTEMPTS {
+            warn!(
+                "Giving up on {} to {} after {} attempts: {}",
+                kind.as_str(),
+                subscription.url,
+                attempt,
+                error
+            );
+            metrics.with_label_values(&["dead_lettered"]).inc();
+            let dead = state.webhooks.bury(DeadLetter {
+                delivery,
+                subscription: subscription.id,
+                url: subscription.url,
+                event: kind,
+                payload,
+                attempts: attempt,
+                error,
+                failed_at: unix_now_millis(),
+            });
+            state.metrics.webhook_dead_letters.set(dead as i64);
+            return;
+        }
+
+        debug!(
+            "Delivering {} to {} failed, attempt {}: {}",
+            kind.as_str(),
+            subscription.url,
+            attempt,
+            error
+        );
+        sleep(FIRST_RETRY * 2u32.pow(attempt - 1)).await;
+        attempt += 1;
+    }
+}
+
+async fn post(
+    state: &AppState,
+    subscription: &Subscription,
+    kind: EventKind,
+    delivery: &str,
+    signature: &str,
+    body: &str,
+) -> Result<(), String> {
+    let request = Request::post(&subscription.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("x-webhook-event", kind.as_str())
+        .header("x-webhook-delivery", delivery)
+        .header("x-webhook-signature", signature)
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|e| e.to_string())?;
+
+    match timeout(DELIVERY_TIMEOUT, state.webhooks.client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => Err(format!("Responded {}", response.status())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("Timed out".into()),
+    }
+}
+
+// HMAC-SHA256 as lowercase hex.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// The option strictly ahead of every other, if any.
+fn leading(counts: &[usize]) -> Option<usize> {
+    let max = *counts.iter().max()?;
+    let mut ahead = counts.iter().enumerate().filter(|(_, &count)| count == max);
+    match (ahead.next(), ahead.next()) {
+        (Some((index, _)), None) if max > 0 => Some(index),
+        _ => None,
+    }
+}
+
+/// Watches the current poll's counts for thresholds and changes of lead.
+pub fn start(state: &Arc<AppState>) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        // Poll index, ballots and leading option as last seen. Counts a poll
+        // already had when it was first watched don't fire anything.
+        let mut watched: Option<(usize, usize, Option<usize>)> = None;
+        let mut interval = interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let index = state.session.index();
+            let snapshot = state.session.current().counters.snapshot();
+            let counts: Vec<usize> = COLORS
+                .iter()
+                .map(|color| snapshot.get(color).unwrap_or_default())
+                .collect();
+            let lead = leading(&counts);
+
+            if let Some((watched_index, ballots, previous_lead)) = watched {
+                if watched_index == index {
+                    notify_where(
+                        &state,
+                        EventKind::Threshold,
+                        index,
+                        json!({}),
+                        |subscription| {
+                            subscription
+                                .thresholds
+                                .iter()
+                                .any(|&t| ballots < t && t <= snapshot.ballots)
+                        },
+                    );
+                    if lead != previous_lead {
+                        if let Some(option) = lead {
+                            notify(
+                                &state,
+                                EventKind::Lead,
+                                index,
+                                json!({ "option": COLORS[option] }),
+                            );
+                        }
+                    }
+                }
+            }
+            watched = Some((index, snapshot.ballots, lead));
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct NewSubscription {
+    url: String,
+    #[serde(default)]
+    events: Vec<EventKind>,
+    #[serde(default)]
+    thresholds: Vec<usize>,
+    // Generated when left out.
+    secret: Option<String>,
+}
+
+/// Lists the subscriptions, without their secrets.
+pub async fn webhooks_handler(_: Admin, State(state): State<Arc<AppState>>) -> Json<Vec<Value>> {
+    debug!("Webhooks requested");
+    Json(
+        state
+            .webhooks
+            .saved()
+            .into_iter()
+            .map(|subscription| {
+                json!({
+                    "id": subscription.id,
+                    "url": subscription.url,
+                    "events": subscription.events,
+                    "thresholds": subscription.thresholds,
+                })
+            })
+            .collect(),
+    )
+}
+
+// Payloads carry poll results and a signature anyone on the path could
+// replay, so they only go out over TLS.
+fn secure(url: &str) -> bool {
+    url.parse::<Uri>()
+        .is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.host().is_some())
+}
+
+/// Subscribes a URL to poll events. The response carries the secret that
+/// signs its payloads; it is not shown again.
+pub async fn subscribe_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Json(new): Json<NewSubscription>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let Some(vault) = &state.webhooks.vault else {
+        return Err(AppError::ServiceUnavailable(
+            "Webhooks are disabled: set RUST_WEBHOOK_KEY, the same on every replica, to enable them"
+                .into(),
+        ));
+    };
+    if new.url.parse::<Uri>().is_err() {
+        return Err(AppError::BadRequest("Invalid webhook URL".into()));
+    }
+    if !secure(&new.url) {
+        return Err(AppError::BadRequest(
+            "Webhook URL must be an https:// URL".into(),
+        ));
+    }
+
+    let secret = new
+        .secret
+        .filter(|secret| !secret.is_empty())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    let subscription = Subscription {
+        id: Uuid::new_v4().to_string(),
+        url: new.url,
+        events: new.events,
+        thresholds: new.thresholds,
+        secret: vault.seal(&secret),
+    };
+    info!(
+        "Webhook {} subscribed for {}",
+        subscription.id, subscription.url
+    );
+    state.webhooks.subscribe(subscription.clone());
+    state.cluster.publish(Gossip::Subscribed {
+        subscription: subscription.clone(),
+    });
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "id": subscription.id,
+            "url": subscription.url,
+            "events": subscription.events,
+            "thresholds": subscription.thresholds,
+            "secret": secret,
+        })),
+    ))
+}
+
+pub async fn unsubscribe_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !state.webhooks.unsubscribe(&id) {
+        return Err(AppError::NotFound(format!("No webhook {id}")));
+    }
+    info!("Webhook {} unsubscribed", id);
+    state.cluster.publish(Gossip::Unsubscribed { id });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deliveries that failed every attempt, oldest first.
+pub async fn dead_letters_handler(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<DeadLetter>> {
+    debug!("Webhook dead letters requested");
+    Json(
+        state
+            .webhooks
+            .dead
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(secret: &str) -> Subscription {
+        Subscription {
+            id: "hook".into(),
+            url: "https://example.com/hook".into(),
+            events: Vec::new(),
+            thresholds: Vec::new(),
+            secret: secret.into(),
+        }
+    }
+
+    #[test]
+    fn secrets_of_older_saves_are_sealed_on_restore() {
+        let webhooks = Webhooks::new(Some("key"));
+        webhooks.restore(&[subscription("plain")]);
+
+        let saved = webhooks.saved();
+        assert!(saved[0].secret.starts_with(SEALED));
+        assert!(!saved[0].secret.contains("plain"));
+        assert_eq!(webhooks.open(&saved[0]).as_deref(), Some("plain"));
+
+        // Another key can't open it, and a missing one can't seal.
+        assert_eq!(Webhooks::new(Some("other")).open(&saved[0]), None);
+        let keyless = Webhooks::new(None);
+        keyless.restore(&[subscription("plain")]);
+        assert!(keyless.saved().is_empty());
+    }
+
+    #[test]
+    fn only_https_urls_are_kept() {
+        let webhooks = Webhooks::new(Some("key"));
+        let plain = Subscription {
+            url: "http://example.com/hook".into(),
+            ..subscription("plain")
+        };
+        webhooks.subscribe(plain.clone());
+        assert!(webhooks.saved().is_empty());
+        webhooks.restore(&[plain]);
+        assert!(webhooks.saved().is_empty());
+
+        assert!(secure("https://example.com/hook"));
+        assert!(!secure("https:///hook"));
+        assert!(!secure("ftp://example.com/hook"));
+    }
+
+    #[test]
+    fn payloads_are_signed_with_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use axum::{
     extract::{
         ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
-        State,
+        Query, State,
     },
     response::IntoResponse,
 };
@@ -9,33 +9,235 @@ use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use serde_json::json;
-use std::sync::{
-    atomic::Ordering::{Acquire, Relaxed},
-    Arc,
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{atomic::Ordering::Relaxed, Arc},
+    time::Duration,
 };
-use tokio::sync::{broadcast::Receiver, Mutex};
-use tracing::{debug, error, warn};
+use tokio::{
+    sync::{
+        broadcast::{
+            error::{RecvError, SendError},
+            Receiver, Sender,
+        },
+        mpsc::{self, error::TrySendError},
+    },
+    time::{interval, timeout, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
-use crate::config::MAX_BYTES;
+use crate::admin::{is_admin_token, Admin};
+use crate::answers::Answer;
+use crate::ballots::{parse_ranking, Cast};
+use crate::cluster::Gossip;
+use crate::config::{MAX_BALLOT_BYTES, MAX_NAME_BYTES, MAX_VOTER_BYTES};
+use crate::counters::{Snapshot, COLORS};
+use crate::crdt::Register;
 use crate::error::AppError;
+use crate::poll::{BallotMode, Poll, PollStatus, Privacy, Visibility};
+use crate::quiz::{start_if_quiz, Question, LEADERBOARD_SIZE};
 use crate::state::AppState;
+use crate::throttle::ClientIp;
+use crate::webhooks::{notify, EventKind};
+
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long a connection asking to present has to send its auth frame.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Votes are batched into one counts update per tick.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+// Room for every reply a single message can get, e.g. an acknowledgement
+// followed by a close.
+const REQUEST_REPLIES: usize = 4;
+
+/// Sent instead of handling a message from a voter or address over its
+/// allowance.
+pub const THROTTLED: &str = "Too many messages, slow down";
 
 enum ClosingSignal {
     WebSocketErr,
     PayloadTooLarge,
     InvalidColor,
-    WebSocketSendErr,
+    InvalidBallot,
+    SlowConsumer,
+}
+
+// Plain text frames are single-choice votes; richer ballots arrive as JSON.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Ballot {
+    Ranked { ranking: Vec<String> },
+    Select { options: Vec<String> },
+    Score { scores: BTreeMap<String, usize> },
+    // Withdraws the voter's latest ballot.
+    Retract,
+    // Replaces the voter's latest ballot; `to` takes the shape the poll's
+    // ballot mode expects.
+    Change { to: Value },
+}
+
+// Session controls, only accepted from presenter connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Command {
+    // Moves everyone to poll `to`, or to the next one.
+    Advance { to: Option<usize> },
+    Reveal,
+    Hide,
+    Lock,
+    Unlock,
+}
+
+// Where the presenter left the session, as replicas share it. Each setting
+// keeps its latest write, so replicas agree whichever order commands
+// reached them in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Controls {
+    current: Register<usize>,
+    polls: Vec<PollControls>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PollControls {
+    locked: Register<bool>,
+    hidden: Register<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct Connect {
+    voter: Option<String>,
+    name: Option<String>,
+    // Asks to present. Browsers cannot set headers on a websocket upgrade,
+    // so unless the upgrade carries the admin token as a bearer token the
+    // first message has to be an `Auth` frame.
+    #[serde(default)]
+    presenter: bool,
+    // Query strings end up in proxy access logs, so a token there is
+    // refused rather than used.
+    token: Option<String>,
+}
+
+// The first frame of a connection that asked to present.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Auth {
+    Auth { token: String },
+}
+
+// Who is on the other end of a connection. The name is only shown on quiz
+// leaderboards.
+struct Participant {
+    voter: Arc<str>,
+    name: Option<String>,
+    presenter: bool,
+    address: IpAddr,
+}
+
+// Producers never await the socket: they enqueue here and the writer task
+// owned by the connection drains the queue into the sink.
+struct Outbound {
+    tx: mpsc::Sender<Message>,
+    state: Arc<AppState>,
+}
+
+impl Outbound {
+    fn send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        let queued = self.tx.max_capacity() - self.tx.capacity();
+        self.state
+            .metrics
+            .outbound_queue_depth
+            .observe(queued as f64);
+        self.tx.try_send(message)
+    }
 }
 
 pub async fn websocket_handler(
     websocket: WebSocketUpgrade,
+    admin: Option<Admin>,
+    ClientIp(address): ClientIp,
+    Query(connect): Query<Connect>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    websocket.on_upgrade(|socket| handle_websocket(socket, state))
+) -> Result<impl IntoResponse, AppError> {
+    if connect.token.is_some() {
+        return Err(AppError::BadRequest(
+            "Send the presenter token in an auth message, not the URL".into(),
+        ));
+    }
+    let authenticate = connect.presenter && admin.is_none();
+    let participant = Participant {
+        voter: voter_id(connect.voter),
+        name: connect.name.and_then(display_name),
+        presenter: admin.is_some(),
+        address,
+    };
+    Ok(websocket.on_upgrade(move |mut socket| async move {
+        let mut participant = participant;
+        if authenticate {
+            if !authenticated(&mut socket, &state).await {
+                return;
+            }
+            participant.presenter = true;
+        }
+        handle_websocket(socket, state, participant).await
+    }))
+}
+
+// Waits for the auth frame of a connection asking to present, closing it
+// unless the frame carries the admin token.
+async fn authenticated(socket: &mut WebSocket, state: &AppState) -> bool {
+    let token = match timeout(AUTH_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(Message::Text(message)))) => match serde_json::from_str(&message) {
+            Ok(Auth::Auth { token }) => Some(token),
+            Err(_) => None,
+        },
+        _ => None,
+    };
+    if token.is_some_and(|token| is_admin_token(state, &token)) {
+        return true;
+    }
+
+    debug!("Presenter connection failed to authenticate");
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "Unauthorized".into(),
+        })))
+        .await;
+    false
+}
+
+// Clients reconnect with the id from their initial message to keep managing
+// the ballots they cast; anything missing or malformed gets a fresh id.
+fn voter_id(requested: Option<String>) -> Arc<str> {
+    match requested {
+        Some(voter) if valid_voter(&voter) => voter.into(),
+        _ => Uuid::new_v4().to_string().into(),
+    }
 }
 
-async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
+/// Whether `voter` can identify a voter: up to `MAX_VOTER_BYTES` letters,
+/// digits, dashes and underscores, such as a UUID.
+pub fn valid_voter(voter: &str) -> bool {
+    !voter.is_empty()
+        && voter.len() <= MAX_VOTER_BYTES.into()
+        && voter
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn display_name(name: String) -> Option<String> {
+    let name = name.trim();
+    (!name.is_empty() && name.len() <= MAX_NAME_BYTES.into() && !name.chars().any(char::is_control))
+        .then(|| name.to_string())
+}
+
+async fn handle_websocket(socket: WebSocket, state: Arc<AppState>, participant: Participant) {
     state.metrics.concurrent_users.inc();
     let count = state.total_users.fetch_add(1, Relaxed);
     state.metrics.total_users.inc();
@@ -44,212 +246,879 @@ async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
         state.concurrent_users.fetch_add(1, Relaxed) + 1
     );
 
-    let rx = state.broadcast_tx.subscribe();
+    let rx = if participant.presenter {
+        state.presenter_tx.subscribe()
+    } else {
+        state.broadcast_tx.subscribe()
+    };
 
     let (ws_sender, ws_receiver) = socket.split();
-    let ws_sender_arc = Arc::new(Mutex::new(ws_sender));
-
-    let handle_messages_sender = Arc::clone(&ws_sender_arc);
-    let handle_messages_state = Arc::clone(&state);
+    let (outbound_tx, outbound_rx) = mpsc::channel(state.config.outbound_high_water);
+    let mut writer = tokio::spawn(handle_outbound(outbound_rx, ws_sender));
 
-    let handle_broadcasts_sender = Arc::clone(&ws_sender_arc);
-    let metrics_state = Arc::clone(&state);
+    let outbound = Outbound {
+        tx: outbound_tx,
+        state: Arc::clone(&state),
+    };
 
-    match send_initial(&count, &state, &ws_sender_arc).await {
-        Ok(()) => {}
+    match send_initial(&count, &participant, &state, &outbound) {
+        Ok(()) => {
+            tokio::select! {
+                _ = handle_messages(ws_receiver, &participant, &outbound, &state) => {},
+                _ = handle_broadcasts(rx, participant.presenter, &outbound) => {},
+            }
+        }
         Err(e) => {
             error!("Sending initial state failed: {}", e);
-            state.concurrent_users.fetch_sub(1, Relaxed);
-            state.metrics.concurrent_users.dec();
-            return;
         }
     }
 
-    tokio::select! {
-        _ = handle_messages(ws_receiver, handle_messages_sender, handle_messages_state) => {},
-        _ = handle_broadcasts(rx, handle_broadcasts_sender) => {},
+    drop(outbound);
+    if timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
     }
 
-    metrics_state.metrics.concurrent_users.dec();
+    state.metrics.concurrent_users.dec();
     debug!(
         "WebSocket connection closed. User count: {}",
-        metrics_state.concurrent_users.fetch_sub(1, Relaxed) - 1
+        state.concurrent_users.fetch_sub(1, Relaxed) - 1
     );
 }
 
+async fn handle_outbound(
+    mut outbound_rx: mpsc::Receiver<Message>,
+    mut ws_sender: SplitSink<WebSocket, Message>,
+) {
+    while let Some(mut message) = outbound_rx.recv().await {
+        // Feed everything already queued and flush once per batch.
+        loop {
+            let closing = matches!(message, Message::Close(_));
+            if let Err(e) = ws_sender.feed(message).await {
+                error!("Websocket sending error: {}", e);
+                return;
+            }
+            if closing {
+                let _ = ws_sender.flush().await;
+                return;
+            }
+            match outbound_rx.try_recv() {
+                Ok(next) => message = next,
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = ws_sender.flush().await {
+            error!("Websocket sending error: {}", e);
+            return;
+        }
+    }
+}
+
 async fn handle_messages(
     mut ws_receiver: SplitStream<WebSocket>,
-    ws_sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    state: Arc<AppState>,
+    participant: &Participant,
+    outbound: &Outbound,
+    state: &Arc<AppState>,
 ) {
     while let Some(result) = ws_receiver.next().await {
         match result {
             Ok(Message::Text(message)) => {
-                if message.len() > MAX_BYTES.into() {
-                    close_connection(ClosingSignal::PayloadTooLarge, &ws_sender, None).await;
+                if message.len() > max_bytes(&message, state.session.current()) {
+                    close_connection(ClosingSignal::PayloadTooLarge, outbound, None);
                     return;
                 }
 
                 debug!("Received payload for: {}", message);
 
-                process_message(&message, &state, &ws_sender).await;
+                if !participant.presenter
+                    && !state
+                        .throttle
+                        .allow(participant.address, &participant.voter)
+                {
+                    if !send_error(THROTTLED, outbound) {
+                        return;
+                    }
+                    continue;
+                }
+
+                if !process_message(&message, participant, state, outbound).await {
+                    return;
+                }
             }
             Ok(_) => {}
             Err(e) => {
-                close_connection(
-                    ClosingSignal::WebSocketErr,
-                    &ws_sender,
-                    Some(&e.to_string()),
-                )
-                .await;
+                close_connection(ClosingSignal::WebSocketErr, outbound, Some(&e.to_string()));
                 return;
             }
         }
     }
 }
 
-async fn handle_broadcasts(
-    mut rx: Receiver<String>,
-    ws_sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-) {
-    while let Ok(msg) = rx.recv().await {
-        let mut sender = ws_sender.lock().await;
-        if let Err(e) = sender.send(Message::Text(msg)).await {
-            close_connection(
-                ClosingSignal::WebSocketSendErr,
-                &ws_sender,
-                Some(&e.to_string()),
-            )
-            .await;
-            return;
+async fn handle_broadcasts(mut rx: Receiver<Message>, presenter: bool, outbound: &Outbound) {
+    loop {
+        let msg = match rx.recv().await {
+            Ok(msg) => msg,
+            // Deltas only carry the options that changed, and status, answer
+            // or leaderboard frames may be among the skipped ones, so the
+            // client is sent everything about the poll over again.
+            Err(RecvError::Lagged(skipped)) => {
+                debug!("Broadcast receiver lagged, skipped {} messages", skipped);
+                let mut resync = poll_payload(&outbound.state, presenter);
+                resync["type"] = json!("poll");
+                match serde_json::to_string(&resync) {
+                    Ok(json) => Message::Text(json.into()),
+                    Err(e) => {
+                        error!("Failed to serialize resync: {}", e);
+                        return;
+                    }
+                }
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        match outbound.send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                close_connection(ClosingSignal::SlowConsumer, outbound, None);
+                return;
+            }
+            Err(TrySendError::Closed(_)) => return,
         }
     }
 }
 
+/// Handles a message sent without a websocket, e.g. over the REST API,
+/// exactly like a frame from a connection. Returns the voter it counted for
+/// and the frames the connection would have been sent back; a close frame
+/// means the message would have ended the connection.
+pub async fn process_request(
+    message: &str,
+    voter: Option<String>,
+    name: Option<String>,
+    presenter: bool,
+    address: IpAddr,
+    state: &Arc<AppState>,
+) -> (Arc<str>, Vec<Message>) {
+    let participant = Participant {
+        voter: voter_id(voter),
+        name: name.and_then(display_name),
+        presenter,
+        address,
+    };
+    let (tx, mut rx) = mpsc::channel(REQUEST_REPLIES);
+    let outbound = Outbound {
+        tx,
+        state: Arc::clone(state),
+    };
+
+    if message.len() > max_bytes(message, state.session.current()) {
+        close_connection(ClosingSignal::PayloadTooLarge, &outbound, None);
+    } else {
+        debug!("Received request payload for: {}", message);
+        process_message(message, &participant, state, &outbound).await;
+    }
+
+    drop(outbound);
+    let mut replies = Vec::new();
+    while let Some(reply) = rx.recv().await {
+        replies.push(reply);
+    }
+    (participant.voter, replies)
+}
+
 async fn process_message(
     message: &str,
+    participant: &Participant,
     state: &Arc<AppState>,
-    ws_sender: &Arc<Mutex<SplitSink<WebSocket, Message>>>,
-) {
-    let state_clone = Arc::clone(state);
-
-    let updated_color = match message {
-        "red" => {
-            state_clone.metrics.votes.with_label_values(&["red"]).inc();
-            state_clone.counters.red.fetch_add(1, Relaxed) + 1
-        }
-        "green" => {
-            state_clone
-                .metrics
-                .votes
-                .with_label_values(&["green"])
-                .inc();
-            state_clone.counters.green.fetch_add(1, Relaxed) + 1
-        }
-        "blue" => {
-            state_clone.metrics.votes.with_label_values(&["blue"]).inc();
-            state_clone.counters.blue.fetch_add(1, Relaxed) + 1
-        }
-        "purple" => {
-            state_clone
-                .metrics
-                .votes
-                .with_label_values(&["purple"])
-                .inc();
-            state_clone.counters.purple.fetch_add(1, Relaxed) + 1
-        }
-        _ => {
-            close_connection(ClosingSignal::InvalidColor, ws_sender, Some(message)).await;
-            return;
+    outbound: &Outbound,
+) -> bool {
+    if message.starts_with('{') {
+        if let Ok(command) = serde_json::from_str::<Command>(message) {
+            return process_command(command, participant, state, outbound);
+        }
+    }
+
+    let poll = state.session.current();
+
+    match poll.status() {
+        PollStatus::Open => {}
+        PollStatus::Scheduled => {
+            return send_error("Poll has not opened yet", outbound);
+        }
+        PollStatus::Closed => {
+            return send_error("Poll is closed", outbound);
+        }
+    }
+    if poll.locked.is_on() {
+        return send_error("Voting is locked", outbound);
+    }
+
+    if is_ballot(message, poll) {
+        let Ok(ballot) = serde_json::from_str::<Ballot>(message) else {
+            close_connection(ClosingSignal::InvalidBallot, outbound, Some(message));
+            return false;
+        };
+        return process_ballot(ballot, participant, poll, outbound);
+    }
+
+    match poll.ballot {
+        BallotMode::Single | BallotMode::Quiz => {}
+        BallotMode::Ranked => return send_error("Poll expects a ranked ballot", outbound),
+        BallotMode::Approval | BallotMode::Multi => {
+            return send_error("Poll expects a selection ballot", outbound);
         }
+        BallotMode::Score => return send_error("Poll expects a score ballot", outbound),
+        BallotMode::Text => {
+            return process_answer(message, &participant.voter, poll, outbound);
+        }
+    }
+
+    let Ok(cast) = parse_color(message) else {
+        close_connection(ClosingSignal::InvalidColor, outbound, Some(message));
+        return false;
     };
 
-    broadcast_update(message, updated_color, state_clone).await;
+    if poll.ballot == BallotMode::Quiz {
+        let index = cast.tallies[0].0;
+        let name = participant.name.as_deref();
+        match state.quiz.answer(&participant.voter, name, index) {
+            // Points stay private until the leaderboard reveals the answer.
+            Ok(player) => {
+                let reply = json!({
+                    "type": "answered",
+                    "player": player,
+                });
+                if !send(&reply, outbound) {
+                    return false;
+                }
+            }
+            Err(reason) => return send_error(reason, outbound),
+        }
+    }
+
+    let name = participant.name.as_deref();
+    poll.ballots
+        .cast(&participant.voter, name, cast, &*poll.counters);
+    true
 }
 
-async fn broadcast_update(message: &str, updated_color: usize, state: Arc<AppState>) {
-    let update = json!({
-        message: updated_color,
-        "total": state.counters.total.fetch_add(1, Relaxed) + 1,
+fn process_command(
+    command: Command,
+    participant: &Participant,
+    state: &Arc<AppState>,
+    outbound: &Outbound,
+) -> bool {
+    if !participant.presenter {
+        return send_error("Only the presenter can control the session", outbound);
+    }
+    if let Err(reason) = control(state, command) {
+        return send_error(reason, outbound);
+    }
+    true
+}
+
+/// Carries out a presenter command here and relays the resulting controls
+/// to the other replicas.
+pub fn control(state: &Arc<AppState>, command: Command) -> Result<(), &'static str> {
+    apply_command(state, &command)?;
+    state.cluster.publish(Gossip::Controls {
+        controls: controls(state),
     });
+    Ok(())
+}
+
+fn apply_command(state: &Arc<AppState>, command: &Command) -> Result<(), &'static str> {
+    let node = &state.session.replica().name;
+    let poll = state.session.current();
+    match *command {
+        Command::Advance { to } => {
+            let index = to.unwrap_or(state.session.index() + 1);
+            if state.session.steer(index).is_none() {
+                return Err("No such poll in the session");
+            }
+            info!("Presenter moved the session to poll {}", index);
+            broadcast_poll(state);
+            start_if_quiz(state);
+            return Ok(());
+        }
+        Command::Reveal => poll.hidden.set(false, node),
+        Command::Hide => poll.hidden.set(true, node),
+        Command::Lock => poll.locked.set(true, node),
+        Command::Unlock => poll.locked.set(false, node),
+    }
+    broadcast_poll(state);
+    Ok(())
+}
+
+/// The presenter's controls as this replica has them.
+pub fn controls(state: &AppState) -> Controls {
+    Controls {
+        current: state.session.shared(),
+        polls: state
+            .session
+            .polls()
+            .iter()
+            .map(|poll| PollControls {
+                locked: poll.locked.shared(),
+                hidden: poll.hidden.shared(),
+            })
+            .collect(),
+    }
+}
+
+/// Takes in the controls of another replica, telling the audience about
+/// whatever changed here.
+pub fn merge_controls(state: &Arc<AppState>, controls: &Controls) {
+    let mut changed = false;
+    for (poll, shared) in state.session.polls().iter().zip(&controls.polls) {
+        changed |= poll.locked.merge(&shared.locked);
+        changed |= poll.hidden.merge(&shared.hidden);
+    }
+    let moved = state.session.merge(&controls.current);
+    if moved {
+        info!(
+            "Presenter moved the session to poll {}",
+            state.session.index()
+        );
+    }
+    if changed || moved {
+        broadcast_poll(state);
+    }
+    if moved {
+        start_if_quiz(state);
+    }
+}
+
+/// Tells the audience the current poll opened or closed on schedule, once
+/// per change. The leader calls this on time and relays it to the others.
+/// Returns whether there was anything to announce.
+pub fn announce_transition(state: &Arc<AppState>) -> bool {
+    let poll = state.session.current();
+    let Some(status) = poll.transition() else {
+        return false;
+    };
+    info!("Poll is now {:?}", status);
+    broadcast_status(state, status);
+    let index = state.session.index();
+    match status {
+        PollStatus::Open => {
+            start_if_quiz(state);
+            notify(state, EventKind::Opened, index, json!({}));
+        }
+        PollStatus::Closed => {
+            if poll.results == Visibility::AfterClose {
+                broadcast_poll(state);
+            }
+            notify(state, EventKind::Closed, index, json!({}));
+        }
+        PollStatus::Scheduled => {}
+    }
+    true
+}
+
+// Answers wait in the moderation queue; only the author hears back until an
+// admin approves them.
+fn process_answer(message: &str, voter: &str, poll: &Poll, outbound: &Outbound) -> bool {
+    let text = message.trim();
+    if text.is_empty() {
+        return send_error("Answer must not be empty", outbound);
+    }
+    if text.chars().any(char::is_control) {
+        return send_error("Answer contains control characters", outbound);
+    }
+
+    let attributed = poll.privacy == Privacy::Attributed;
+    let id = match poll.answers.submit(voter, attributed, text) {
+        Ok(id) => id,
+        Err(reason) => return send_error(reason, outbound),
+    };
+    send(
+        &json!({
+            "type": "submitted",
+            "id": id,
+        }),
+        outbound,
+    )
+}
+
+fn process_ballot(
+    ballot: Ballot,
+    participant: &Participant,
+    poll: &Poll,
+    outbound: &Outbound,
+) -> bool {
+    if matches!(ballot, Ballot::Retract) && !poll.ballots.amendable() {
+        return send_error(unamendable(poll), outbound);
+    }
+
+    let voter = &participant.voter;
+    match ballot {
+        Ballot::Retract => {
+            poll.ballots.retract(voter, &*poll.counters)
+                || send_error("No vote to retract", outbound)
+        }
+        Ballot::Change { to } => {
+            let cast = match parse_change(to, poll) {
+                Ok(cast) => cast,
+                Err(reason) => return send_error(reason, outbound),
+            };
+            poll.ballots.change(voter, cast, &*poll.counters)
+                || send_error("No vote to change", outbound)
+        }
+        ballot => match parse_ballot(ballot, poll) {
+            Ok(cast) => {
+                let name = participant.name.as_deref();
+                poll.ballots.cast(voter, name, cast, &*poll.counters);
+                true
+            }
+            Err(reason) => send_error(reason, outbound),
+        },
+    }
+}
+
+fn parse_ballot(ballot: Ballot, poll: &Poll) -> Result<Cast, &'static str> {
+    match ballot {
+        Ballot::Ranked { ranking } => {
+            if poll.ballot != BallotMode::Ranked {
+                return Err("Poll does not accept ranked ballots");
+            }
+
+            // First preferences keep the live counters moving; the full
+            // ranking feeds the runoff.
+            let ranking = parse_ranking(&ranking)?;
+            Ok(Cast {
+                tallies: vec![(ranking[0].into(), 1)],
+                ranking,
+            })
+        }
+        Ballot::Select { options } => {
+            if !matches!(poll.ballot, BallotMode::Approval | BallotMode::Multi) {
+                return Err("Poll does not accept selection ballots");
+            }
+
+            let indexes = parse_selection(&options, poll.max_choices)?;
+            Ok(Cast {
+                tallies: indexes.iter().map(|&i| (i, 1)).collect(),
+                ranking: Vec::new(),
+            })
+        }
+        Ballot::Score { scores } => {
+            if poll.ballot != BallotMode::Score {
+                return Err("Poll does not accept score ballots");
+            }
+
+            Ok(Cast {
+                tallies: parse_scores(&scores, poll.max_score)?,
+                ranking: Vec::new(),
+            })
+        }
+        Ballot::Retract | Ballot::Change { .. } => Err("Ballot cannot be nested"),
+    }
+}
+
+// A change carries the replacement in the same shape as a fresh ballot for
+// the poll: a color, a ranking, a list of options or a map of scores.
+fn parse_change(to: Value, poll: &Poll) -> Result<Cast, &'static str> {
+    if !poll.ballots.amendable() {
+        return Err(unamendable(poll));
+    }
+    let ballot = match poll.ballot {
+        BallotMode::Single => {
+            let color = to.as_str().ok_or("Change must name a single option")?;
+            return parse_color(color).map_err(|_| "Change contains an unknown option");
+        }
+        BallotMode::Ranked => serde_json::from_value(to).map(|ranking| Ballot::Ranked { ranking }),
+        BallotMode::Approval | BallotMode::Multi => {
+            serde_json::from_value(to).map(|options| Ballot::Select { options })
+        }
+        BallotMode::Score => serde_json::from_value(to).map(|scores| Ballot::Score { scores }),
+        BallotMode::Text | BallotMode::Quiz => return Err(unamendable(poll)),
+    };
+
+    parse_ballot(
+        ballot.map_err(|_| "Change does not match the poll's ballot")?,
+        poll,
+    )
+}
 
-    match serde_json::to_string(&update) {
-        Ok(json) => {
-            if let Err(e) = state.broadcast_tx.send(json) {
-                warn!("Failed to broadcast update: {}", e);
+fn unamendable(poll: &Poll) -> &'static str {
+    match poll.ballot {
+        BallotMode::Text => "Answers cannot be changed",
+        BallotMode::Quiz => "Quiz answers cannot be changed",
+        _ => "Votes on anonymous polls are final",
+    }
+}
+
+// Text polls take every frame as an answer, even one that looks like JSON.
+fn is_ballot(message: &str, poll: &Poll) -> bool {
+    poll.ballot != BallotMode::Text && message.starts_with('{')
+}
+
+fn max_bytes(message: &str, poll: &Poll) -> usize {
+    if is_ballot(message, poll) {
+        MAX_BALLOT_BYTES.into()
+    } else {
+        poll.max_bytes
+    }
+}
+
+fn parse_color(color: &str) -> Result<Cast, ()> {
+    let index = COLORS.iter().position(|c| *c == color).ok_or(())?;
+    Ok(Cast {
+        tallies: vec![(index, 1)],
+        ranking: Vec::new(),
+    })
+}
+
+// Score ballots move the averages of every option, so their deltas always
+// carry all of them. Otherwise only the touched options, in display order.
+fn changed(poll: &Poll, colors: &[&str]) -> Vec<&'static str> {
+    COLORS
+        .into_iter()
+        .filter(|color| poll.ballot == BallotMode::Score || colors.contains(color))
+        .collect()
+}
+
+/// Broadcasts the current poll's counts whenever votes have moved them,
+/// taking one snapshot per batch rather than one per vote. A change is
+/// written in a single counter write, so its old and new options always
+/// reach clients in the same delta.
+pub fn start(state: &Arc<AppState>) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut ticker = interval(UPDATE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let poll = state.session.current();
+            let colors = poll.counters.take_changed();
+            if !colors.is_empty() {
+                let snapshot = poll.counters.snapshot();
+                broadcast_update(&changed(poll, &colors), snapshot, poll, &state);
             }
         }
+    });
+}
+
+// Options left out of a score ballot score zero.
+fn parse_scores(
+    scores: &BTreeMap<String, usize>,
+    max_score: usize,
+) -> Result<Vec<(usize, usize)>, &'static str> {
+    if scores.is_empty() {
+        return Err("Score ballot must score at least one option");
+    }
+
+    let mut tallies = Vec::with_capacity(scores.len());
+    for (option, &score) in scores {
+        let index = COLORS
+            .iter()
+            .position(|color| color == option)
+            .ok_or("Score ballot contains an unknown option")?;
+        if score > max_score {
+            return Err("Score is above the poll maximum");
+        }
+        tallies.push((index, score));
+    }
+
+    Ok(tallies)
+}
+
+fn parse_selection(options: &[String], max_choices: usize) -> Result<Vec<usize>, &'static str> {
+    if options.is_empty() {
+        return Err("Selection must include at least one option");
+    }
+    if options.len() > max_choices {
+        return Err("Selection includes too many options");
+    }
+
+    let mut indexes = Vec::with_capacity(options.len());
+    for option in options {
+        let index = COLORS
+            .iter()
+            .position(|color| color == option)
+            .ok_or("Selection contains an unknown option")?;
+        if indexes.contains(&index) {
+            return Err("Selection lists an option more than once");
+        }
+        indexes.push(index);
+    }
+
+    Ok(indexes)
+}
+
+pub fn broadcast_update(colors: &[&str], snapshot: Snapshot, poll: &Poll, state: &AppState) {
+    let mut update = Map::new();
+    for color in colors {
+        update.insert(color.to_string(), json!(snapshot.get(color)));
+    }
+    update.insert("total".into(), json!(snapshot.total));
+    update.insert("ballots".into(), json!(snapshot.ballots));
+    if poll.ballot == BallotMode::Score {
+        update.insert("averages".into(), json!(snapshot.averages()));
+    }
+
+    if !poll.results_visible() {
+        // The audience still sees how many have voted.
+        let participation = json!({ "ballots": snapshot.ballots });
+        broadcast_split(state, &Value::Object(update), &participation);
+    } else {
+        broadcast(state, &Value::Object(update));
+    }
+}
+
+pub fn broadcast_status(state: &AppState, status: PollStatus) {
+    let poll = state.session.current();
+    let update = json!({
+        "type": "status",
+        "status": status,
+        "opens_at": poll.opens_at,
+        "closes_at": poll.closes_at,
+    });
+
+    broadcast(state, &update);
+}
+
+pub fn broadcast_runoff(state: &AppState) {
+    let poll = state.session.current();
+    let runoff = poll.ballots.runoff();
+    let update = json!({
+        "type": "runoff",
+        "rounds": runoff.rounds,
+        "winner": runoff.winner,
+    });
+
+    broadcast_results(state, poll, &update);
+}
+
+pub fn broadcast_answer(state: &AppState, answer: &Answer) {
+    let mut update = answer.published();
+    update["type"] = json!("answer");
+
+    broadcast_results(state, state.session.current(), &update);
+}
+
+/// Takes back an answer that was shown before another replica's rejection
+/// overruled its approval.
+pub fn broadcast_retraction(state: &AppState, answer: &Answer) {
+    let update = json!({
+        "type": "retracted",
+        "id": answer.id,
+    });
+
+    broadcast_results(state, state.session.current(), &update);
+}
+
+pub fn broadcast_wordcloud(state: &AppState) {
+    let poll = state.session.current();
+    let update = json!({
+        "type": "wordcloud",
+        "words": poll.wordcloud.top(),
+    });
+
+    broadcast_results(state, poll, &update);
+}
+
+pub fn broadcast_question(state: &AppState, question: &Question) {
+    let mut update = json!(question);
+    update["type"] = json!("question");
+
+    broadcast(state, &update);
+}
+
+pub fn broadcast_leaderboard(state: &AppState, question: &Question, correct: usize) {
+    let update = json!({
+        "type": "leaderboard",
+        "question": question.number,
+        "correct": COLORS[correct],
+        "leaderboard": state.quiz.leaderboard(LEADERBOARD_SIZE),
+    });
+
+    broadcast(state, &update);
+}
+
+// Everything about the current poll, sent whenever the presenter changes
+// it so audience sockets follow along.
+pub fn broadcast_poll(state: &AppState) {
+    let mut update = poll_payload(state, true);
+    update["type"] = json!("poll");
+    let mut audience = poll_payload(state, false);
+    audience["type"] = json!("poll");
+
+    broadcast_split(state, &update, &audience);
+}
+
+// Results of a hidden poll, approved answers included, only reach
+// presenters.
+fn broadcast_results(state: &AppState, poll: &Poll, update: &Value) {
+    if !poll.results_visible() {
+        send_frame(&state.presenter_tx, update);
+    } else {
+        broadcast(state, update);
+    }
+}
+
+fn broadcast(state: &AppState, update: &Value) {
+    broadcast_split(state, update, update);
+}
+
+fn broadcast_split(state: &AppState, update: &Value, audience: &Value) {
+    if let Some(Err(e)) = send_frame(&state.broadcast_tx, audience) {
+        warn!("Failed to broadcast update: {}", e);
+    }
+    // Usually nobody is presenting.
+    send_frame(&state.presenter_tx, update);
+}
+
+fn send_frame(tx: &Sender<Message>, update: &Value) -> Option<Result<usize, SendError<Message>>> {
+    match serde_json::to_string(update) {
+        Ok(json) => Some(tx.send(Message::Text(json.into()))),
         Err(e) => {
             error!("Failed to serialize update: {}", e);
+            None
         }
     }
 }
 
-async fn close_connection(
-    signal: ClosingSignal,
-    ws_sender: &Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    error_info: Option<&str>,
-) {
-    let message = match signal {
+// Rejections the client can recover from are reported without closing the
+// socket. Returns whether the connection is still usable.
+fn send_error(reason: &str, outbound: &Outbound) -> bool {
+    debug!("Rejected message: {}", reason);
+    send(
+        &json!({
+            "type": "error",
+            "message": reason,
+        }),
+        outbound,
+    )
+}
+
+// Replies to this connection only. Returns whether it is still usable.
+fn send(message: &Value, outbound: &Outbound) -> bool {
+    match serde_json::to_string(message) {
+        Ok(json) => match outbound.send(Message::Text(json.into())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                close_connection(ClosingSignal::SlowConsumer, outbound, None);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        },
+        Err(e) => {
+            error!("Failed to serialize reply: {}", e);
+            true
+        }
+    }
+}
+
+fn close_connection(signal: ClosingSignal, outbound: &Outbound, error_info: Option<&str>) {
+    let (code, message) = match signal {
         ClosingSignal::WebSocketErr => {
             error!(
                 "Websocket error: {}",
                 error_info.unwrap_or("unknown websocket error")
             );
-            "Websocket Error"
+            (close_code::INVALID, "Websocket Error")
         }
         ClosingSignal::PayloadTooLarge => {
             error!("Payload abnormal: larger than max bytes");
-            "Abnormal Payload"
+            (close_code::INVALID, "Abnormal Payload")
         }
         ClosingSignal::InvalidColor => {
             error!(
                 "Invalid color received: {}",
                 error_info.unwrap_or("unknown color")
             );
-            "Invalid Color"
+            (close_code::INVALID, "Invalid Color")
         }
-        ClosingSignal::WebSocketSendErr => {
+        ClosingSignal::InvalidBallot => {
             error!(
-                "Websocket sending error: {}",
-                error_info.unwrap_or("unknown color")
+                "Invalid ballot received: {}",
+                error_info.unwrap_or("unknown ballot")
             );
-            "Websocket Sending Error"
+            (close_code::INVALID, "Invalid Ballot")
+        }
+        ClosingSignal::SlowConsumer => {
+            warn!("Slow consumer: outbound queue reached the high-water mark");
+            outbound.state.metrics.slow_consumers.inc();
+            (close_code::POLICY, "Slow Consumer")
         }
     };
-    let mut sender = ws_sender.lock().await;
-    let _ = sender
-        .send(Message::Close(Some(CloseFrame {
-            code: close_code::INVALID,
-            reason: message.into(),
-        })))
-        .await;
+    // A full queue means the writer is already behind; dropping the queue
+    // afterwards ends the connection either way.
+    let _ = outbound.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: message.into(),
+    })));
 }
 
-async fn send_initial(
+fn send_initial(
     count: &usize,
+    participant: &Participant,
     state: &Arc<AppState>,
-    ws_sender: &Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    outbound: &Outbound,
 ) -> Result<(), AppError> {
     let message = json!({
         "type": "users",
         "count": count,
     });
-    let json = serde_json::to_string(&message)?;
-    state.broadcast_tx.send(json)?;
+    broadcast(state, &message);
 
-    let initial = json!({
-        "type": "initial",
-        "count": count,
-        "red": state.counters.red.load(Acquire),
-        "green": state.counters.green.load(Acquire),
-        "blue": state.counters.blue.load(Acquire),
-        "purple": state.counters.purple.load(Acquire),
-        "total": state.counters.total.load(Acquire),
-    });
+    let mut initial = poll_payload(state, participant.presenter);
+    initial["type"] = json!("initial");
+    initial["count"] = json!(count);
+    initial["voter"] = json!(&*participant.voter);
+    initial["presenter"] = json!(participant.presenter);
     let json = serde_json::to_string(&initial)?;
 
-    let mut sender = ws_sender.lock().await;
-    sender.send(Message::Text(json)).await?;
+    outbound.send(Message::Text(json.into()))?;
     Ok(())
 }
+
+// The current poll's settings and results. Counts of a hidden poll read as
+// zero for the audience, apart from the number of ballots.
+pub fn poll_payload(state: &AppState, privileged: bool) -> Value {
+    let index = state.session.index();
+    let poll = state.session.current();
+    let hidden = !poll.results_visible();
+    let visible = privileged || !hidden;
+    let snapshot = poll.counters.snapshot();
+    let shown = if visible {
+        snapshot
+    } else {
+        Snapshot {
+            ballots: snapshot.ballots,
+            ..Snapshot::default()
+        }
+    };
+
+    json!({
+        "poll": index,
+        "polls": state.session.polls().len(),
+        "title": poll.title,
+        "red": shown.red,
+        "green": shown.green,
+        "blue": shown.blue,
+        "purple": shown.purple,
+        "total": shown.total,
+        "ballots": shown.ballots,
+        "averages": (poll.ballot == BallotMode::Score).then(|| shown.averages()),
+        "status": poll.status(),
+        "locked": poll.locked.is_on(),
+        "hidden": hidden,
+        "results": poll.results,
+        "privacy": poll.privacy,
+        "ballot": poll.ballot,
+        "max_choices": poll.max_choices,
+        "max_score": poll.max_score,
+        "max_bytes": poll.max_bytes,
+        "answers": (visible && poll.ballot == BallotMode::Text).then(|| poll.answers.published()),
+        "wordcloud": (visible && poll.ballot == BallotMode::Text).then(|| poll.wordcloud.top()),
+        "quiz_seconds": (poll.ballot == BallotMode::Quiz).then_some(poll.seconds),
+        "question": state.quiz.current(index),
+        "leaderboard": state.quiz.finished(index).then(|| state.quiz.leaderboard(LEADERBOARD_SIZE)),
+        "runoff": (visible && poll.ballot == BallotMode::Ranked).then(|| poll.ballots.runoff()),
+        "opens_at": poll.opens_at,
+        "closes_at": poll.closes_at,
+    })
+}
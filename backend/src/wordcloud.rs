@@ -0,0 +1,298 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+};
+
+const MIN_WORD_CHARS: usize = 2;
+
+const STOP_WORDS: [&str; 118] = [
+    "a", "about", "above", "after", "again", "against", "all", "am", "an", "and", "any", "are",
+    "as", "at", "be", "because", "been", "before", "being", "below", "between", "both", "but",
+    "by", "can", "could", "did", "do", "does", "doing", "down", "during", "each", "few", "for",
+    "from", "further", "had", "has", "have", "having", "he", "her", "here", "hers", "him", "his",
+    "how", "i", "if", "in", "into", "is", "it", "its", "just", "me", "more", "most", "my", "no",
+    "nor", "not", "now", "of", "off", "on", "once", "only", "or", "other", "our", "ours", "out",
+    "over", "own", "same", "she", "should", "so", "some", "such", "than", "that", "the", "their",
+    "theirs", "them", "then", "there", "these", "they", "this", "those", "through", "to", "too",
+    "under", "until", "up", "very", "was", "we", "were", "what", "when", "where", "which", "while",
+    "who", "whom", "why", "will", "with", "would", "you", "your", "yours",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Word {
+    pub word: String,
+    pub count: usize,
+}
+
+// Spellings seen for one stem, so the cloud shows "running" rather than
+// the stem "run" when that is what people actually wrote.
+#[derive(Default)]
+struct Entry {
+    count: usize,
+    forms: HashMap<String, usize>,
+}
+
+impl Entry {
+    fn display(&self) -> &str {
+        self.forms
+            .iter()
+            .max_by(|(a, x), (b, y)| x.cmp(y).then_with(|| b.cmp(a)))
+            .map_or("", |(form, _)| form.as_str())
+    }
+}
+
+// Word frequencies over the approved answers of a text poll, keyed by stem.
+pub struct WordCloud {
+    top_k: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+    dirty: AtomicBool,
+}
+
+impl WordCloud {
+    pub fn new(top_k: usize) -> Self {
+        Self {
+            top_k,
+            entries: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn add(&self, text: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        for word in words(text) {
+            let entry = entries.entry(stem(&word)).or_default();
+            entry.count += 1;
+            *entry.forms.entry(word).or_default() += 1;
+        }
+        self.dirty.store(true, Relaxed);
+    }
+
+    /// Takes back the words of an answer passed to `add`.
+    pub fn remove(&self, text: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        for word in words(text) {
+            let stem = stem(&word);
+            let Some(entry) = entries.get_mut(&stem) else {
+                continue;
+            };
+            entry.count -= 1;
+            if let Some(count) = entry.forms.get_mut(&word) {
+                *count -= 1;
+                if *count == 0 {
+                    entry.forms.remove(&word);
+                }
+            }
+            if entry.count == 0 {
+                entries.remove(&stem);
+            }
+        }
+        self.dirty.store(true, Relaxed);
+    }
+
+    /// Whether words were added or removed since the last call.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Relaxed)
+    }
+
+    /// The `top_k` most frequent words, most frequent first. Ties are broken
+    /// alphabetically so repeated broadcasts are stable.
+    pub fn top(&self) -> Vec<Word> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut top: Vec<Word> = entries
+            .values()
+            .map(|entry| Word {
+                word: entry.display().to_string(),
+                count: entry.count,
+            })
+            .collect();
+        top.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.word.cmp(&b.word)));
+        top.truncate(self.top_k);
+        top
+    }
+}
+
+// Case-folded words with stop words and very short tokens removed.
+// Apostrophes are dropped so "don't" and "dont" count together.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '’')
+        .map(|token| {
+            token
+                .chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+// Step 1 of the Porter stemmer: plurals, -ed/-ing and a trailing y. That is
+// enough to merge the inflections a short answer tends to use without the
+// over-stemming of the later steps.
+fn stem(word: &str) -> String {
+    if !word.is_ascii() || word.len() <= 3 {
+        return word.to_string();
+    }
+    let mut stem = word.to_string();
+
+    if stem.ends_with("sses") || stem.ends_with("ies") {
+        stem.truncate(stem.len() - 2);
+    } else if stem.ends_with('s') && !stem.ends_with("ss") && !stem.ends_with("us") {
+        stem.pop();
+    }
+
+    if stem.ends_with("eed") {
+        if measure(&stem[..stem.len() - 3]) > 0 {
+            stem.pop();
+        }
+    } else if let Some(suffix) = ["ed", "ing"].into_iter().find(|s| stem.ends_with(s)) {
+        let base = &stem[..stem.len() - suffix.len()];
+        if has_vowel(base) {
+            stem.truncate(base.len());
+            if stem.ends_with("at") || stem.ends_with("bl") || stem.ends_with("iz") {
+                stem.push('e');
+            } else if ends_with_double_consonant(&stem)
+                && !(stem.ends_with('l') || stem.ends_with('s') || stem.ends_with('z'))
+            {
+                stem.pop();
+            } else if measure(&stem) == 1 && ends_cvc(&stem) {
+                stem.push('e');
+            }
+        }
+    }
+
+    if stem.ends_with('y') && has_vowel(&stem[..stem.len() - 1]) {
+        stem.pop();
+        stem.push('i');
+    }
+
+    stem
+}
+
+fn is_consonant(word: &[u8], i: usize) -> bool {
+    match word[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(word, i - 1),
+        _ => true,
+    }
+}
+
+fn has_vowel(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    (0..bytes.len()).any(|i| !is_consonant(bytes, i))
+}
+
+// Number of vowel-consonant sequences, the "m" of the Porter paper.
+fn measure(word: &str) -> usize {
+    let bytes = word.as_bytes();
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for i in 0..bytes.len() {
+        let vowel = !is_consonant(bytes, i);
+        if previous_vowel && !vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+    count
+}
+
+fn ends_with_double_consonant(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let n = bytes.len();
+    n >= 2 && bytes[n - 1] == bytes[n - 2] && is_consonant(bytes, n - 1)
+}
+
+fn ends_cvc(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let n = bytes.len();
+    n >= 3
+        && is_consonant(bytes, n - 3)
+        && !is_consonant(bytes, n - 2)
+        && is_consonant(bytes, n - 1)
+        && !matches!(bytes[n - 1], b'w' | b'x' | b'y')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stems_inflections() {
+        for (word, stemmed) in [
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("cats", "cat"),
+            ("caress", "caress"),
+            ("census", "census"),
+            ("agreed", "agree"),
+            ("feed", "feed"),
+            ("plastered", "plaster"),
+            ("motoring", "motor"),
+            ("sing", "sing"),
+            ("conflated", "conflate"),
+            ("troubled", "trouble"),
+            ("sized", "size"),
+            ("hopping", "hop"),
+            ("falling", "fall"),
+            ("hissing", "hiss"),
+            ("filing", "file"),
+            ("failing", "fail"),
+            ("happy", "happi"),
+            ("sky", "sky"),
+            ("café", "café"),
+        ] {
+            assert_eq!(stem(word), stemmed, "stem of {word}");
+        }
+    }
+
+    #[test]
+    fn splits_and_folds_words() {
+        for (text, expected) in [
+            ("Hello, WORLD!", &["hello", "world"][..]),
+            ("Don't stop; dont-stop", &["dont", "stop", "dont", "stop"]),
+            ("It’s fine", &["fine"]),
+            ("I think that it is what it is", &["think"]),
+            ("a b c 42", &["42"]),
+            ("Ünïcode Straße", &["ünïcode", "straße"]),
+            ("", &[]),
+        ] {
+            assert_eq!(
+                words(text).collect::<Vec<_>>(),
+                expected,
+                "words of {text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn top_counts_stems_and_cuts_at_k() {
+        let cloud = WordCloud::new(2);
+        cloud.add("Running late, running again");
+        cloud.add("We run. Dogs bark at the dog");
+        cloud.add("bark");
+
+        let top: Vec<_> = cloud
+            .top()
+            .into_iter()
+            .map(|word| (word.word, word.count))
+            .collect();
+        // "dog" ties with "bark" on two but loses alphabetically.
+        assert_eq!(top, [("running".into(), 3), ("bark".into(), 2)]);
+        assert!(cloud.take_dirty());
+        assert!(!cloud.take_dirty());
+
+        cloud.remove("Running late, running again");
+        let top: Vec<_> = cloud
+            .top()
+            .into_iter()
+            .map(|word| (word.word, word.count))
+            .collect();
+        assert_eq!(top, [("bark".into(), 2), ("dog".into(), 2)]);
+        assert!(cloud.take_dirty());
+    }
+}